@@ -20,6 +20,14 @@ pub enum SubCommand {
     Render(RenderArgs),
     /// score_def.yamlを生成する（従来のgenerate-scoreコマンド）
     GenerateScore,
+    /// VSCファイルを正規化した形に整形する
+    Format {
+        #[arg(long)]
+        input: String,
+        /// 出力先（省略時は標準出力）
+        #[arg(long)]
+        output: Option<String>,
+    },
 }
 
 #[derive(ClapArgs)]
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(clippy::empty_line_after_doc_comments)]
 
+use std::fmt;
 use std::str::FromStr;
 
 /**
@@ -15,23 +16,196 @@ pub struct Score {
 #[derive(Debug, Clone)]
 pub struct Part {
     pub name: String,
+    pub instrument: Option<String>, // `instrument=clarinet_bb`（INSTRUMENTS の楽器名。音部記号・移調の既定値になる）
+    pub clefs: Vec<String>,         // `clef=treble+bass`。譜表ごとのClef型の名前（空なら自動）
+    pub transposition: Option<i32>, // `transpose=-2`。実音との差（半音単位）
+    pub staff_count: usize, // 譜表数（大譜表なら2）
     pub measures: Vec<Measure>,
+    pub lyrics: Vec<Lyric>, // `#[Lyrics]` の歌詞（音符・和音のidで対応付ける）
 }
 
-/// Measure represents a single measure and contains a collection of beats.
+/// Lyric is one syllable sung on a note or chord, written in a `#[Lyrics]` block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lyric {
+    pub note: NoteRef,      // 歌詞を付ける音符・和音
+    pub staff: usize,
+    pub voice: usize,
+    pub verse: usize,       // 1始まりの番（`#[Lyrics(verse=2)]`）
+    pub text: String,
+    pub syllabic: Syllabic,
+    pub extender: bool,     // `love_` 後続の音（メリスマ）へ延ばす線
+}
+
+/// Syllabic tells where a syllable sits in its word (`Hel- lo` → Begin, End).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syllabic {
+    Single,
+    Begin,
+    Middle,
+    End,
+}
+
+impl Syllabic {
+    /// 前の音節が `-` で続いているかと、この音節の後に `-` があるかから決める
+    pub fn from_hyphens(after_hyphen: bool, hyphen: bool) -> Syllabic {
+        match (after_hyphen, hyphen) {
+            (false, false) => Syllabic::Single,
+            (false, true) => Syllabic::Begin,
+            (true, true) => Syllabic::Middle,
+            (true, false) => Syllabic::End,
+        }
+    }
+
+    /// 後に `-` が付く（次の音節へ続く）かどうか
+    pub fn has_hyphen(&self) -> bool {
+        matches!(self, Syllabic::Begin | Syllabic::Middle)
+    }
+
+    /// score_def.yamlでの名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            Syllabic::Single => "single",
+            Syllabic::Begin => "begin",
+            Syllabic::Middle => "middle",
+            Syllabic::End => "end",
+        }
+    }
+}
+
+/// Measure represents a single measure and contains one beat list per voice.
 #[derive(Debug, Clone)]
 pub struct Measure {
     pub number: usize, // 小節番号を追加
     pub meter: (usize, usize), // (numerator, denominator)
+    pub beat_groups: Vec<usize>, // 拍ごとの分母単位数（例: 6/8 → [3, 3]、2+2+3/8 → [2, 2, 3]）
     pub duration: f32,        // 小節全体のDuration（例: 4.0）
     pub unit_duration: f32,   // 小節内の1単位のDuration（例: 1.0）
+    pub length: MeasureLength, // 弱起・不完全小節の指定
+    pub marks: MeasureMarks,  // 反復記号・括弧・D.C./D.S.など
+    pub voices: Vec<Voice>,   // (譜表番号, 声部番号)順
+}
+
+/// MeasureLength tells whether a measure is as long as its meter says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasureLength {
+    /// 拍子どおりの長さの小節
+    Full,
+    /// 弱起（`pickup`）。先頭の休符だけの拍を除いた長さになる
+    Pickup,
+    /// 拍数を指定した不完全小節（`partial(n)`）
+    Partial(usize),
+}
+
+/// MeasureMarks holds the repeat and navigation signs, and the tempo and key changes, written on a measure.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MeasureMarks {
+    pub repeat_start: bool,        // `|:` 小節の始めの反復記号
+    pub repeat_end: Option<usize>, // `:|` / `:|x3` 小節の終わりの反復記号（演奏回数）
+    pub volta: Vec<usize>,         // `volta(1,2)` この小節を演奏する回目
+    pub segno: bool,               // `segno`
+    pub coda: bool,                // `coda` コーダの始まり
+    pub to_coda: bool,             // `to_coda` 戻った後はここからコーダへ飛ぶ
+    pub fine: bool,                // `fine` 戻った後はこの小節で終わる
+    pub jump: Option<Jump>,        // `D.C.` / `D.S.` など、小節の終わりで戻る
+    pub tempo: Vec<TempoChange>,   // `tempo=96` / `tempo=72@3`
+    pub key: Vec<KeyChange>,       // `key=Eb` / `key=Cm@3`
+}
+
+/// TempoChange is a tempo written on a measure line (`tempo=96@3`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempoChange {
+    pub position: num_rational::Ratio<i32>, // 小節内の位置（score_def.yamlと同じく拍子の単位で1始まり）
+    pub bpm: u32,
+}
+
+/// KeyChange is a key signature written on a measure line (`key=Eb@3`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub position: num_rational::Ratio<i32>, // 小節内の位置（score_def.yamlと同じく拍子の単位で1始まり）
+    pub key: String,                        // KeyType型の名前（"E_Flat_Major" など）
+}
+
+/// `key=` に書ける調（小文字の `m` で短調）と、score_def.yamlのKeyType型の名前
+pub const KEYS: &[(&str, &str)] = &[
+    ("none", "none"),
+    ("C", "C_Major"), ("Am", "A_Minor"),
+    ("G", "G_Major"), ("Em", "E_Minor"),
+    ("D", "D_Major"), ("Bm", "B_Minor"),
+    ("A", "A_Major"), ("F#m", "F_Sharp_Minor"),
+    ("E", "E_Major"), ("C#m", "C_Sharp_Minor"),
+    ("B", "B_Major"), ("G#m", "G_Sharp_Minor"),
+    ("F#", "F_Sharp_Major"), ("D#m", "D_Sharp_Minor"),
+    ("G#", "G_Sharp_Major"), ("A#m", "A_Sharp_Minor"),
+    ("F", "F_Major"), ("Dm", "D_Minor"),
+    ("Bb", "B_Flat_Major"), ("Gm", "G_Minor"),
+    ("Eb", "E_Flat_Major"), ("Cm", "C_Minor"),
+    ("Ab", "A_Flat_Major"), ("Fm", "F_Minor"),
+    ("Db", "D_Flat_Major"), ("Bbm", "B_Flat_Minor"),
+    ("Gb", "G_Flat_Major"), ("Ebm", "E_Flat_Minor"),
+    ("Cb", "C_Flat_Major"), ("Abm", "A_Flat_Minor"),
+];
+
+/// Jump is a D.C. / D.S. instruction at the end of a measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    DaCapo,         // D.C.
+    DaCapoAlFine,   // D.C. al Fine
+    DaCapoAlCoda,   // D.C. al Coda
+    DalSegno,       // D.S.
+    DalSegnoAlFine, // D.S. al Fine
+    DalSegnoAlCoda, // D.S. al Coda
+}
+
+impl Jump {
+    /// VSCでの表記
+    pub const SPELLINGS: &'static [(&'static str, Jump)] = &[
+        ("D.C.", Jump::DaCapo),
+        ("D.C.al_Fine", Jump::DaCapoAlFine),
+        ("D.C.al_Coda", Jump::DaCapoAlCoda),
+        ("D.S.", Jump::DalSegno),
+        ("D.S.al_Fine", Jump::DalSegnoAlFine),
+        ("D.S.al_Coda", Jump::DalSegnoAlCoda),
+    ];
+
+    /// VSCでの表記からJumpを得る
+    pub fn from_spelling(s: &str) -> Option<Jump> {
+        Self::SPELLINGS.iter().find(|(spelling, _)| *spelling == s).map(|&(_, jump)| jump)
+    }
+
+    /// VSCでの表記（`D.S.al_Coda` など）
+    pub fn spelling(&self) -> &'static str {
+        Self::SPELLINGS.iter().find(|(_, jump)| jump == self).map_or("", |(spelling, _)| spelling)
+    }
+
+    /// セーニョへ戻るかどうか（falseなら曲頭へ戻る）
+    pub fn returns_to_segno(&self) -> bool {
+        matches!(self, Jump::DalSegno | Jump::DalSegnoAlFine | Jump::DalSegnoAlCoda)
+    }
+}
+
+/// Voice represents one independent rhythmic line within a measure (`#[Voice(n)]`),
+/// written on one staff of the part (`#[Staff(n)]`).
+#[derive(Debug, Clone)]
+pub struct Voice {
+    pub number: usize, // 1始まりの声部番号（譜表ごと）
+    pub staff: usize,  // 1始まりの譜表番号
     pub beats: Vec<Beat>,
+    pub measure_rest: Option<MeasureRest>, // `R` の小節（拍は小節全体の休符1つ）
+}
+
+/// MeasureRest marks a voice that rests for the whole measure (`R`),
+/// possibly as one measure of a multi-measure rest (`12-20: R`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeasureRest {
+    pub first: usize, // 連続する休符の最初の小節番号
+    pub count: usize, // 連続する小節数（`R` だけなら1）
 }
 
 /// Beat represents a single beat and stores ScoreElements that lie on a fixed grid.
 #[derive(Debug, Clone)]
 pub struct Beat {
     pub duration: f32, // 拍のDurationを追加
+    pub units: usize,  // 拍が占める拍子の分母単位数（6/8 を2拍で書けば3）
     pub elements: Vec<ScoreElement>,
 }
 
@@ -51,6 +225,12 @@ pub struct Tie {
     pub id: Option<u64>,
     pub pitch: Option<Pitch>,
     pub pitch_cents: Option<u16>,
+    /// The note, tie or chord this continuation is tied from
+    pub tied_from: Option<NoteRef>,
+    /// True when the continuation moves to its own pitch (`t(C#5)`, `t+50c`): a pitch slide from `tied_from`
+    pub slide: bool,
+    /// True when the id was written explicitly (`t@4`) and stays fixed across edits
+    pub anchored: bool,
     pub duration: num_rational::Ratio<i32>, // 分数で保持
 }
 
@@ -59,14 +239,239 @@ pub struct Tie {
 pub struct Event {
     /// Unique ID for this event object
     pub id: Option<u64>,
+    /// True when the id was written explicitly (`C5@12`) and stays fixed across edits
+    pub anchored: bool,
     pub event_type: EventType,
     pub pitch: Option<Pitch>,
     /// MIDI note number × 100 ( = cents )。rest のときは None
     pub pitch_cents: Option<u16>,
     pub tie: bool,
+    /// The earlier note this one continues (chord member `t`, or a note matching a `-` tie)
+    pub tied_from: Option<NoteRef>,
+    /// True when this chord member is reached by a pitch slide from `tied_from` (`{t(E5), t}`)
+    pub slide: bool,
+    /// Grace notes written before this note (`(D5 E5)C5`); they take no metric time
+    pub grace_notes: Vec<GraceNote>,
+    /// Articulations written as token suffixes (`C5.stacc`, `E5>`)
+    pub articulations: Vec<Articulation>,
+    /// Dynamic marking written as a token suffix (`G5!ff`), as a DynamicsLevel name (`FF`)
+    pub dynamic: Option<String>,
+    /// Percussion sound written by name (`kick`, `hh.open`); `pitch` then holds its MIDI key
+    pub unpitched: Option<Unpitched>,
     pub duration: num_rational::Ratio<i32>, // 分数で保持
 }
 
+/// Unpitched is how a named percussion sound is notated, resolved through the drum map.
+#[derive(Debug, Clone)]
+pub struct Unpitched {
+    pub name: String,     // VSCでの名前（`hh.open`）
+    pub key: u8,          // 鳴らすMIDIキー
+    pub display: Pitch,   // 譜表上の位置（打楽器譜でこの音高の位置に書く）
+    pub notehead: String, // NOTEHEADS のいずれか
+}
+
+/// DrumSound is one entry of a drum map: a name, the MIDI key to play and how to notate it.
+#[derive(Debug, Clone)]
+pub struct DrumSound {
+    pub name: String,
+    pub key: u8,
+    pub display: Pitch,
+    pub notehead: String,
+}
+
+/// 打楽器の符頭
+pub const NOTEHEADS: &[&str] = &["normal", "x", "circle_x", "diamond", "triangle", "slash", "square"];
+
+/// 組み込みのドラムマップ（General MIDIの打楽器）。名前、MIDIキー、譜表上の位置、符頭
+pub const GM_DRUMS: &[(&str, u8, &str, &str)] = &[
+    ("kick.acoustic", 35, "E4", "normal"),
+    ("kick", 36, "F4", "normal"),
+    ("sidestick", 37, "C5", "circle_x"),
+    ("snare", 38, "C5", "normal"),
+    ("clap", 39, "C5", "x"),
+    ("snare.electric", 40, "C5", "normal"),
+    ("tom.floor.low", 41, "F4", "normal"),
+    ("hh", 42, "G5", "x"),
+    ("tom.floor", 43, "A4", "normal"),
+    ("hh.pedal", 44, "D4", "x"),
+    ("tom.low", 45, "B4", "normal"),
+    ("hh.open", 46, "G5", "circle_x"),
+    ("tom.lowmid", 47, "D5", "normal"),
+    ("tom.himid", 48, "D5", "normal"),
+    ("crash", 49, "A5", "x"),
+    ("tom.high", 50, "E5", "normal"),
+    ("ride", 51, "F5", "x"),
+    ("china", 52, "B5", "x"),
+    ("ride.bell", 53, "F5", "diamond"),
+    ("tambourine", 54, "E5", "x"),
+    ("splash", 55, "B5", "x"),
+    ("cowbell", 56, "E5", "triangle"),
+    ("crash2", 57, "B5", "x"),
+    ("vibraslap", 58, "D5", "x"),
+    ("ride2", 59, "E5", "x"),
+    ("bongo.high", 60, "E5", "normal"),
+    ("bongo.low", 61, "D5", "normal"),
+    ("conga.mute", 62, "C5", "x"),
+    ("conga.open", 63, "C5", "normal"),
+    ("conga.low", 64, "A4", "normal"),
+    ("timbale.high", 65, "E5", "normal"),
+    ("timbale.low", 66, "D5", "normal"),
+    ("agogo.high", 67, "E5", "triangle"),
+    ("agogo.low", 68, "D5", "triangle"),
+    ("cabasa", 69, "B4", "x"),
+    ("maracas", 70, "B4", "x"),
+    ("whistle.short", 71, "G5", "normal"),
+    ("whistle.long", 72, "G5", "normal"),
+    ("guiro.short", 73, "C5", "x"),
+    ("guiro.long", 74, "C5", "x"),
+    ("claves", 75, "B4", "x"),
+    ("woodblock.high", 76, "E5", "triangle"),
+    ("woodblock.low", 77, "D5", "triangle"),
+    ("cuica.mute", 78, "E5", "x"),
+    ("cuica.open", 79, "E5", "normal"),
+    ("triangle.mute", 80, "A5", "triangle"),
+    ("triangle", 81, "A5", "triangle"),
+];
+
+impl DrumSound {
+    /// 組み込みのドラムマップ
+    pub fn general_midi() -> Vec<DrumSound> {
+        GM_DRUMS.iter()
+            .filter_map(|&(name, key, display, notehead)| Some(DrumSound {
+                name: name.to_string(),
+                key,
+                display: display.parse().ok()?,
+                notehead: notehead.to_string(),
+            }))
+            .collect()
+    }
+
+    /// この音を鳴らす音符の情報
+    pub fn unpitched(&self) -> Unpitched {
+        Unpitched { name: self.name.clone(), key: self.key, display: self.display.clone(), notehead: self.notehead.clone() }
+    }
+}
+
+/// GraceNote is an ornamental note attached to the following main note.
+#[derive(Debug, Clone)]
+pub struct GraceNote {
+    /// Unique ID for this grace note (numbered before its main note)
+    pub id: Option<u64>,
+    pub kind: GraceKind,
+    pub pitch: Pitch,
+    pub pitch_cents: Option<u16>,
+}
+
+/// GraceKind distinguishes slashed and unslashed grace notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraceKind {
+    Acciaccatura, // 短前打音 `(/D5)C5`
+    Appoggiatura, // 長前打音 `(D5)C5`
+}
+
+/// Articulation is a playing technique mark attached to a note (`C5.stacc`, `E5>`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Articulation {
+    Staccato,
+    Tenuto,
+    Accent,
+    Marcato,
+    Fermata,
+    BowUp,
+    BowDown,
+    Trill,
+}
+
+impl Articulation {
+    /// VSCでの表記（`.` に続く名前。アクセントは `>` とも書ける）
+    pub const SPELLINGS: &'static [(&'static str, Articulation)] = &[
+        ("stacc", Articulation::Staccato),
+        ("ten", Articulation::Tenuto),
+        ("acc", Articulation::Accent),
+        ("marc", Articulation::Marcato),
+        ("ferm", Articulation::Fermata),
+        ("up", Articulation::BowUp),
+        ("down", Articulation::BowDown),
+        ("tr", Articulation::Trill),
+    ];
+
+    /// VSCでの表記からArticulationを得る
+    pub fn from_spelling(s: &str) -> Option<Articulation> {
+        Self::SPELLINGS.iter().find(|(spelling, _)| *spelling == s).map(|&(_, art)| art)
+    }
+
+    /// VSCでの表記（`stacc` など、`.` を除いたもの）
+    pub fn spelling(&self) -> &'static str {
+        Self::SPELLINGS.iter().find(|(_, art)| art == self).map_or("", |(spelling, _)| spelling)
+    }
+
+    /// score_def.yamlでの名前（`staccato` など）
+    pub fn name(&self) -> &'static str {
+        match self {
+            Articulation::Staccato => "staccato",
+            Articulation::Tenuto => "tenuto",
+            Articulation::Accent => "accent",
+            Articulation::Marcato => "marcato",
+            Articulation::Fermata => "fermata",
+            Articulation::BowUp => "bow_up",
+            Articulation::BowDown => "bow_down",
+            Articulation::Trill => "trill",
+        }
+    }
+}
+
+/// `!` に続けて書ける強弱記号（score_def.yamlのDynamicsLevelを小文字にしたもの）
+pub const DYNAMICS_LEVELS: &[&str] = &[
+    "ppp", "pp", "p", "mp", "mf", "f", "ff", "fff", "sf", "sfz", "sffz", "rfz",
+    "sfp", "sfpp", "sfmp", "fp", "fpp", "fmp", "mfp", "mfpp", "mfmp", "ffp", "ffpp", "ffmp",
+];
+
+/// `clef=` に書ける音部記号と、score_def.yamlのClef型の名前
+pub const CLEFS: &[(&str, &str)] = &[
+    ("treble", "Treble"), ("bass", "Bass"), ("alto", "Alto"), ("tenor", "Tenor"),
+    ("soprano", "Soprano"), ("mezzo_soprano", "MezzoSoprano"), ("baritone_c", "BaritoneC"),
+    ("baritone_f", "BaritoneF"), ("sub_bass", "SubBass"), ("french", "French"),
+    ("percussion", "Percussion"), ("none", "None"),
+    ("treble8va", "Treble8va"), ("treble8vb", "Treble8vb"), ("treble15ma", "Treble15ma"), ("treble15mb", "Treble15mb"),
+    ("bass8va", "Bass8va"), ("bass8vb", "Bass8vb"), ("bass15ma", "Bass15ma"), ("bass15mb", "Bass15mb"),
+];
+
+/// 組み込みの楽器（`instrument=` の値）ごとの音部記号と移調（半音単位）
+pub const INSTRUMENTS: &[(&str, &[&str], i32)] = &[
+    ("piano", &["Treble", "Bass"], 0),
+    ("harp", &["Treble", "Bass"], 0),
+    ("organ", &["Treble", "Bass"], 0),
+    ("flute", &["Treble"], 0),
+    ("piccolo", &["Treble"], 12),
+    ("oboe", &["Treble"], 0),
+    ("english_horn", &["Treble"], -7),
+    ("clarinet_bb", &["Treble"], -2),
+    ("clarinet_a", &["Treble"], -3),
+    ("bass_clarinet", &["Treble"], -14),
+    ("bassoon", &["Bass"], 0),
+    ("soprano_sax", &["Treble"], -2),
+    ("alto_sax", &["Treble"], -9),
+    ("tenor_sax", &["Treble"], -14),
+    ("baritone_sax", &["Treble"], -21),
+    ("horn_f", &["Treble"], -7),
+    ("trumpet_bb", &["Treble"], -2),
+    ("trombone", &["Bass"], 0),
+    ("tuba", &["Bass"], 0),
+    ("violin", &["Treble"], 0),
+    ("viola", &["Alto"], 0),
+    ("cello", &["Bass"], 0),
+    ("double_bass", &["Bass"], -12),
+    ("guitar", &["Treble"], -12),
+    ("drums", &["Percussion"], 0),
+];
+
+/// NoteRef points at a note, tie or chord by measure number and id (the score_def.yaml key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NoteRef {
+    pub measure: usize,
+    pub id: u64,
+}
+
 /// EventType distinguishes between notes and rests.
 #[derive(Debug, Clone, PartialEq)]
 pub enum EventType {
@@ -77,8 +482,11 @@ pub enum EventType {
 /// Subdivision is used when subdividing a basic unit further (e.g., tuplets).
 #[derive(Debug, Clone)]
 pub struct Subdivision {
+    /// Total number of units the parent duration is split into (= sum of `weights`).
     pub base_division: u32,
     pub elements: Vec<ScoreElement>,
+    /// Number of units each element occupies (`C5*3` → 3, default 1).
+    pub weights: Vec<u32>,
 }
 
 /// Chord represents a chord with multiple simultaneous sounding events.
@@ -86,6 +494,8 @@ pub struct Subdivision {
 pub struct Chord {
     /// Unique ID for this chord object
     pub id: Option<u64>,
+    /// True when the id was written explicitly (`{C5, E5}@7`) and stays fixed across edits
+    pub anchored: bool,
     pub events: Vec<Event>,
 }
 
@@ -107,10 +517,81 @@ pub enum NoteLetter {
     A, B, C, D, E, F, G,
 }
 
-#[derive(Debug, Clone)]
+/// 臨時記号。入力された綴りをそのまま保持する（`NaturalSharp` と `Sharp` は音高が同じでも区別する）。
+#[derive(Debug, Clone, PartialEq)]
 pub enum Accidental {
-    Sharp,
-    Flat,
+    // 通常臨時記号
+    Natural,      // n
+    Sharp,        // #
+    Flat,         // b
+    DoubleSharp,  // x または ##
+    DoubleFlat,   // bb
+    NaturalSharp, // n#
+    NaturalFlat,  // nb
+    // 四分音
+    QuarterSharp,      // +
+    QuarterFlat,       // d
+    ThreeQuarterSharp, // #+
+    ThreeQuarterFlat,  // bd
+}
+
+impl Accidental {
+    /// Accidental spellings accepted after the note letter, longest first.
+    const SPELLINGS: &'static [(&'static str, Accidental)] = &[
+        ("##", Accidental::DoubleSharp),
+        ("bb", Accidental::DoubleFlat),
+        ("n#", Accidental::NaturalSharp),
+        ("nb", Accidental::NaturalFlat),
+        ("#+", Accidental::ThreeQuarterSharp),
+        ("bd", Accidental::ThreeQuarterFlat),
+        ("x", Accidental::DoubleSharp),
+        ("#", Accidental::Sharp),
+        ("b", Accidental::Flat),
+        ("n", Accidental::Natural),
+        ("+", Accidental::QuarterSharp),
+        ("d", Accidental::QuarterFlat),
+        ("♯", Accidental::Sharp),
+        ("♭", Accidental::Flat),
+        ("♮", Accidental::Natural),
+        ("𝄪", Accidental::DoubleSharp),
+        ("𝄫", Accidental::DoubleFlat),
+    ];
+
+    /// The canonical VSC spelling of this accidental (the first listed in `SPELLINGS`).
+    pub fn spelling(&self) -> &'static str {
+        Self::SPELLINGS.iter().find(|(_, acc)| acc == self).map_or("", |(spelling, _)| spelling)
+    }
+
+    /// Split a leading accidental off `s`, returning it and the remaining text.
+    fn strip_prefix(s: &str) -> (Option<Accidental>, &str) {
+        for (spelling, acc) in Self::SPELLINGS {
+            if let Some(rest) = s.strip_prefix(spelling) {
+                return (Some(acc.clone()), rest);
+            }
+        }
+        (None, s)
+    }
+
+    /// Pitch deviation from the natural letter in cents.
+    pub fn cents_offset(&self) -> i32 {
+        match self {
+            Accidental::Natural => 0,
+            Accidental::Sharp | Accidental::NaturalSharp => 100,
+            Accidental::Flat | Accidental::NaturalFlat => -100,
+            Accidental::DoubleSharp => 200,
+            Accidental::DoubleFlat => -200,
+            Accidental::QuarterSharp => 50,
+            Accidental::QuarterFlat => -50,
+            Accidental::ThreeQuarterSharp => 150,
+            Accidental::ThreeQuarterFlat => -150,
+        }
+    }
+
+    /// Whole-semitone shift used for MIDI numbers.
+    /// Quarter tones are truncated toward the written letter (e.g. `ThreeQuarterSharp` → +1).
+    pub fn semitone_offset(&self) -> i32 {
+        self.cents_offset() / 100
+    }
 }
 
 /// Duration: 整数部と分数部で表現する持続時間
@@ -121,12 +602,15 @@ pub struct Duration {
     pub frac_den: i32,    // 分母（0不可、整数のみの場合は1）
 }
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 /// --- Unique ID generator for score objects ---
+#[derive(Default)]
 pub struct IdGenerator {
     current: u64,
     used: HashSet<u64>,
+    /// `@N` で固定された要素: 生成したID → 指定されたID
+    anchors: HashMap<u64, u64>,
 }
 
 impl IdGenerator {
@@ -153,14 +637,31 @@ impl IdGenerator {
     pub fn remove(&mut self, id: u64) {
         self.used.remove(&id);
     }
-}
-
-impl Default for IdGenerator {
-    fn default() -> Self {
-        Self {
-            current: 0,
-            used: HashSet::new(),
+    /// 生成済みのIDを `@N` で指定されたIDに固定する（同じ小節で同じ番号は使えない）
+    pub fn anchor(&mut self, id: u64, anchor: u64) -> Result<(), String> {
+        if self.anchors.values().any(|&a| a == anchor) {
+            return Err(format!("Id @{} is used more than once in this measure", anchor));
         }
+        self.anchors.insert(id, anchor);
+        Ok(())
+    }
+    /// 生成したID → 最終的なID。固定されたIDはそのまま使い、
+    /// それ以外は生成順に、固定されたIDを飛ばして1から振り直す
+    pub fn final_ids(&self) -> HashMap<u64, u64> {
+        let reserved: HashSet<u64> = self.anchors.values().copied().collect();
+        let mut generated: Vec<u64> = self.used.iter().copied().collect();
+        generated.sort_unstable();
+        let mut next = 0;
+        generated.into_iter().map(|id| {
+            let final_id = self.anchors.get(&id).copied().unwrap_or_else(|| {
+                next += 1;
+                while reserved.contains(&next) {
+                    next += 1;
+                }
+                next
+            });
+            (id, final_id)
+        }).collect()
     }
 }
 
@@ -177,36 +678,145 @@ impl FromStr for Pitch {
 
         let mut chars = s.chars();
         let letter_char = chars.next().ok_or("Empty input")?;
-        let letter = match letter_char.to_ascii_uppercase() {
-            'A' => NoteLetter::A,
-            'B' => NoteLetter::B,
-            'C' => NoteLetter::C,
-            'D' => NoteLetter::D,
-            'E' => NoteLetter::E,
-            'F' => NoteLetter::F,
-            'G' => NoteLetter::G,
-            _   => return Err(format!("Invalid note letter: {}", letter_char)),
-        };
+        let letter = NoteLetter::from_char(letter_char)?;
 
         // accidental?
-        let mut accidental = None;
-        let rest: String;
-        if let Some(ch) = chars.next() {
-            if ch == '#' || ch == 'b' {
-                accidental = Some(if ch == '#' { Accidental::Sharp } else { Accidental::Flat });
-                rest = chars.collect();
-            } else {
-                rest = std::iter::once(ch).chain(chars).collect();
-            }
-        } else {
+        let rest = chars.as_str();
+        if rest.is_empty() {
             return Err("Missing octave information".into());
         }
+        let (accidental, rest) = Accidental::strip_prefix(rest);
 
         let octave: i32 = rest.parse().map_err(|_| "Invalid octave".to_string())?;
         Ok(Pitch::NoteName { letter, accidental, octave })
     }
 }
 
+/// Formats a pitch in VSC spelling (`C#5`, `Bb3`, or a MIDI number), the inverse of `from_str`.
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pitch::Midi(n) => write!(f, "{}", n),
+            Pitch::NoteName { letter, accidental, octave } => {
+                let accidental = accidental.as_ref().map_or("", Accidental::spelling);
+                write!(f, "{:?}{}{}", letter, accidental, octave)
+            }
+        }
+    }
+}
+
+/// Highest representable `pitch_cents` value (just below MIDI 128).
+pub const MAX_PITCH_CENTS: i32 = 12799;
+
+impl Pitch {
+    /// Parse a pitch that may carry a microtonal cents suffix and return it together with
+    /// its `pitch_cents` value.
+    ///
+    /// - `C5`, `72`         : ordinary pitch (`pitch_cents` = MIDI × 100)
+    /// - `C5+31c`, `72-14c` : pitch deviated by the given number of cents
+    /// - `6031c`            : raw cents literal (MIDI × 100 + cents); the pitch becomes the nearest MIDI note
+    pub fn parse_with_cents(s: &str) -> Result<(Pitch, Option<u16>), String> {
+        let Some(body) = s.strip_suffix('c') else {
+            let pitch = s.parse::<Pitch>()?;
+            let cents = pitch.cents().ok();
+            return Ok((pitch, cents));
+        };
+
+        // 音名/MIDI番号 + 偏差
+        if let Some(i) = body.rfind(['+', '-']).filter(|&i| i > 0) {
+            let pitch = body[..i].parse::<Pitch>()?;
+            let deviation: i32 = body[i..].parse()
+                .map_err(|_| format!("Invalid cents offset `{}c`", &body[i..]))?;
+            let cents = pitch.cents()? as i32 + deviation;
+            check_pitch_cents(cents, s)?;
+            return Ok((pitch, Some(cents as u16)));
+        }
+
+        // セント値そのもの
+        let cents: i32 = body.parse().map_err(|_| format!("Invalid cents literal `{}`", s))?;
+        check_pitch_cents(cents, s)?;
+        let nearest = ((cents + 50) / 100).min(127) as u8;
+        Ok((Pitch::Midi(nearest), Some(cents as u16)))
+    }
+}
+
+impl Pitch {
+    /// Whether `s` is a relative-mode spelling: a note letter and optional accidental with no
+    /// octave, followed by any number of `'` (octave up) and `,` (octave down) marks.
+    pub fn is_relative_spelling(s: &str) -> bool {
+        let body = s.trim_end_matches(['\'', ',']);
+        let mut chars = body.chars();
+        matches!(chars.next(), Some('A'..='G' | 'a'..='g'))
+            && Accidental::strip_prefix(chars.as_str()).1.is_empty()
+    }
+
+    /// Parse a relative-mode spelling (`E`, `F#'`, `Bb,,`), placing the letter in the octave
+    /// closest to `reference` (at most a fourth away) and then applying the octave marks.
+    pub fn parse_relative(s: &str, reference: &Pitch) -> Result<Pitch, String> {
+        if !Self::is_relative_spelling(s) {
+            return Err(format!("Invalid relative pitch `{}`", s));
+        }
+        let body = s.trim_end_matches(['\'', ',']);
+        let shift: i32 = s[body.len()..].chars().map(|c| if c == '\'' { 1 } else { -1 }).sum();
+        let mut chars = body.chars();
+        let letter = NoteLetter::from_char(chars.next().unwrap_or('C'))?;
+        let (accidental, _) = Accidental::strip_prefix(chars.as_str());
+        let step = letter.diatonic_index();
+        let octave = (reference.diatonic_step()? - step + 3).div_euclid(7) - 1 + shift;
+        let pitch = Pitch::NoteName { letter, accidental, octave };
+        pitch.cents()?;
+        Ok(pitch)
+    }
+
+    /// Diatonic position counted in letter steps from C-1 (`C4` = 35). MIDI numbers use the
+    /// sharp spelling.
+    fn diatonic_step(&self) -> Result<i32, String> {
+        match self {
+            Pitch::NoteName { letter, octave, .. } => Ok((octave + 1) * 7 + letter.diatonic_index()),
+            Pitch::Midi(n) => {
+                const STEPS: [i32; 12] = [0, 0, 1, 1, 2, 3, 3, 4, 4, 5, 5, 6];
+                Ok((*n as i32 / 12) * 7 + STEPS[*n as usize % 12])
+            }
+        }
+    }
+}
+
+impl NoteLetter {
+    fn from_char(c: char) -> Result<NoteLetter, String> {
+        match c.to_ascii_uppercase() {
+            'A' => Ok(NoteLetter::A),
+            'B' => Ok(NoteLetter::B),
+            'C' => Ok(NoteLetter::C),
+            'D' => Ok(NoteLetter::D),
+            'E' => Ok(NoteLetter::E),
+            'F' => Ok(NoteLetter::F),
+            'G' => Ok(NoteLetter::G),
+            _ => Err(format!("Invalid note letter: {}", c)),
+        }
+    }
+
+    /// 0 for C up to 6 for B
+    fn diatonic_index(&self) -> i32 {
+        match self {
+            NoteLetter::C => 0,
+            NoteLetter::D => 1,
+            NoteLetter::E => 2,
+            NoteLetter::F => 3,
+            NoteLetter::G => 4,
+            NoteLetter::A => 5,
+            NoteLetter::B => 6,
+        }
+    }
+}
+
+fn check_pitch_cents(cents: i32, s: &str) -> Result<(), String> {
+    if (0..=MAX_PITCH_CENTS).contains(&cents) {
+        Ok(())
+    } else {
+        Err(format!("`{}` is out of range (0c to {}c)", s, MAX_PITCH_CENTS))
+    }
+}
+
 /// --- Pitch utility methods (ownership‑safe) -----------------------------------------------
 
 impl Pitch {
@@ -225,16 +835,12 @@ impl Pitch {
                     NoteLetter::A => 9,
                     NoteLetter::B => 11,
                 };
-                let shift = match accidental {
-                    Some(Accidental::Sharp) => 1,
-                    Some(Accidental::Flat)  => -1,
-                    None                    => 0,
-                };
+                let shift = accidental.as_ref().map_or(0, Accidental::semitone_offset);
                 let midi = (octave + 1) * 12 + base + shift;
                 if (0..=127).contains(&midi) {
                     Ok(midi as u8)
                 } else {
-                    Err(format!("Pitch {:?} out of MIDI range", self))
+                    Err(format!("Pitch {} out of MIDI range", self))
                 }
             }
         }
@@ -245,8 +851,184 @@ impl Pitch {
         self.midi_number()
     }
 
-    /// Convert to “cents” representation (MIDI × 100).
+    /// Convert to “cents” representation (MIDI × 100), including quarter-tone accidentals.
     pub fn cents(&self) -> Result<u16, String> {
-        self.midi_number().map(|n| (n as u16) * 100)
+        match self {
+            Pitch::NoteName { accidental: Some(acc), .. } => {
+                let midi = self.midi_number()? as i32;
+                // 半音未満の残り（四分音）を加える
+                let cents = midi * 100 + acc.cents_offset() - acc.semitone_offset() * 100;
+                if (0..=12700).contains(&cents) {
+                    Ok(cents as u16)
+                } else {
+                    Err(format!("Pitch {} out of MIDI range", self))
+                }
+            }
+            _ => self.midi_number().map(|n| (n as u16) * 100),
+        }
+    }
+
+    /// Transpose by whole semitones, keeping any quarter-tone remainder.
+    /// Note names are respelled with the smallest accidental, preferring flats when the
+    /// original was spelled with a flat.
+    pub fn transposed(&self, semitones: i32) -> Result<Pitch, String> {
+        let out_of_range = || format!("Pitch {} transposed by {} semitones is out of MIDI range", self, semitones);
+        let Pitch::NoteName { accidental, .. } = self else {
+            let midi = self.midi_number()? as i32 + semitones;
+            return u8::try_from(midi).ok().filter(|m| *m <= 127).map(Pitch::Midi).ok_or_else(out_of_range);
+        };
+        let cents = self.cents()? as i32 + semitones * 100;
+        if !(0..=12700).contains(&cents) {
+            return Err(out_of_range());
+        }
+        let prefer_flat = accidental.as_ref().is_some_and(|acc| acc.cents_offset() < 0);
+        let candidates = [
+            None,
+            Some(Accidental::Sharp), Some(Accidental::Flat),
+            Some(Accidental::QuarterSharp), Some(Accidental::QuarterFlat),
+            Some(Accidental::ThreeQuarterSharp), Some(Accidental::ThreeQuarterFlat),
+            Some(Accidental::DoubleSharp), Some(Accidental::DoubleFlat),
+        ];
+        let letters = [
+            (NoteLetter::C, 0), (NoteLetter::D, 2), (NoteLetter::E, 4), (NoteLetter::F, 5),
+            (NoteLetter::G, 7), (NoteLetter::A, 9), (NoteLetter::B, 11),
+        ];
+        let octave = cents / 1200 - 1;
+        (octave - 1..=octave + 1)
+            .flat_map(|o| letters.iter().map(move |(letter, base)| (o, letter, ((o + 1) * 12 + base) * 100)))
+            .flat_map(|(o, letter, letter_cents)| {
+                candidates.iter()
+                    .filter(move |acc| letter_cents + acc.as_ref().map_or(0, Accidental::cents_offset) == cents)
+                    .map(move |acc| (o, letter, acc))
+            })
+            .min_by_key(|(_, _, acc)| {
+                let offset = acc.as_ref().map_or(0, Accidental::cents_offset);
+                (offset.abs(), (offset < 0) != prefer_flat)
+            })
+            .map(|(octave, letter, accidental)| Pitch::NoteName { letter: letter.clone(), accidental: accidental.clone(), octave })
+            .ok_or_else(out_of_range)
+    }
+
+    /// Accidental name as written in score_def.yaml (`"None"` when not spelled out).
+    pub fn accidental_name(&self) -> String {
+        match self {
+            Pitch::NoteName { accidental: Some(acc), .. } => format!("{:?}", acc),
+            _ => "None".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transpose_error_names_pitch() {
+        let pitch: Pitch = "G9".parse().unwrap();
+        assert_eq!(
+            pitch.transposed(12).unwrap_err(),
+            "Pitch G9 transposed by 12 semitones is out of MIDI range"
+        );
+    }
+
+    #[test]
+    fn anchor_rejects_same_number_twice() {
+        let mut id_gen = IdGenerator::default();
+        let (a, b) = (id_gen.next_id(), id_gen.next_id());
+        assert!(id_gen.anchor(a, 3).is_ok());
+        assert!(id_gen.anchor(b, 3).is_err());
+        assert_eq!(id_gen.final_ids()[&a], 3);
+    }
+
+    #[test]
+    fn final_ids_skip_anchored_numbers() {
+        let mut id_gen = IdGenerator::default();
+        let ids: Vec<u64> = (0..4).map(|_| id_gen.next_id()).collect();
+        id_gen.anchor(ids[2], 1).unwrap();
+        let final_ids = id_gen.final_ids();
+        let mapped: Vec<u64> = ids.iter().map(|id| final_ids[id]).collect();
+        assert_eq!(mapped, vec![2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn anchored_id_survives_insertion_before_it() {
+        // 固定した要素の前に1つ挿入しても、固定したIDと挿入位置より前のIDは変わらない
+        let layout = |inserted: bool| {
+            let mut id_gen = IdGenerator::default();
+            let before = id_gen.next_id();
+            if inserted {
+                id_gen.next_id();
+            }
+            let anchored = id_gen.next_id();
+            id_gen.anchor(anchored, 2).unwrap();
+            let after = id_gen.next_id();
+            let final_ids = id_gen.final_ids();
+            (final_ids[&before], final_ids[&anchored], final_ids[&after])
+        };
+        let (before, anchored, after) = layout(false);
+        let (before_ins, anchored_ins, after_ins) = layout(true);
+        assert_eq!((before, anchored), (before_ins, anchored_ins));
+        assert_eq!((before, anchored, after), (1, 2, 3));
+        assert_eq!(after_ins, 4);
+    }
+
+    /// 音名の `pitch_cents`
+    fn cents(s: &str) -> u16 {
+        s.parse::<Pitch>().unwrap().cents().unwrap()
+    }
+
+    #[test]
+    fn cents_follow_every_accidental() {
+        assert_eq!(cents("C5"), 7200);
+        assert_eq!(cents("Cn5"), 7200);
+        assert_eq!(cents("C#5"), 7300);
+        assert_eq!(cents("Db5"), 7300);
+        assert_eq!(cents("C##5"), 7400);
+        assert_eq!(cents("Ebb5"), 7400);
+        assert_eq!(cents("Bn#4"), 7200);
+        assert_eq!(cents("Dnb5"), 7300);
+        // 四分音
+        assert_eq!(cents("C+5"), 7250);
+        assert_eq!(cents("Dd5"), 7350);
+        assert_eq!(cents("C#+5"), 7350);
+        assert_eq!(cents("Dbd5"), 7250);
+        // MIDI番号と音域外
+        assert_eq!(cents("72"), 7200);
+        assert!("Cb-1".parse::<Pitch>().unwrap().cents().is_err());
+        assert!("G#9".parse::<Pitch>().unwrap().cents().is_err());
+    }
+
+    #[test]
+    fn alternative_accidental_spellings_are_equivalent() {
+        let accidental = |s: &str| match s.parse::<Pitch>().unwrap() {
+            Pitch::NoteName { accidental, .. } => accidental,
+            other => panic!("expected a note name, got {:?}", other),
+        };
+        assert_eq!(accidental("Cx5"), Some(Accidental::DoubleSharp));
+        assert_eq!(accidental("C𝄪5"), Some(Accidental::DoubleSharp));
+        assert_eq!(accidental("C♯5"), Some(Accidental::Sharp));
+        assert_eq!(accidental("D♭5"), Some(Accidental::Flat));
+        assert_eq!(accidental("E𝄫5"), Some(Accidental::DoubleFlat));
+        assert_eq!(accidental("C♮5"), Some(Accidental::Natural));
+        assert_eq!(accidental("Cn5"), Some(Accidental::Natural));
+        assert_eq!(accidental("Ed5"), Some(Accidental::QuarterFlat));
+        assert_eq!(accidental("C5"), None);
+        assert_eq!(cents("Cx5"), cents("C##5"));
+        assert_eq!(cents("C♯5"), cents("C#5"));
+        assert!("Cq5".parse::<Pitch>().is_err());
+    }
+
+    #[test]
+    fn printed_spelling_keeps_the_written_accidental() {
+        // 異名同音に置き換えず、書いた臨時記号を保つ（別表記は標準の書き方になる）
+        let printed = |s: &str| s.parse::<Pitch>().unwrap().to_string();
+        assert_eq!(printed("E#4"), "E#4");
+        assert_eq!(printed("Fb4"), "Fb4");
+        assert_eq!(printed("Cn5"), "Cn5");
+        assert_eq!(printed("Bd4"), "Bd4");
+        assert_eq!(printed("F#+4"), "F#+4");
+        assert_eq!(printed("Cx5"), "C##5");
+        assert_eq!(printed("D♭5"), "Db5");
+        assert_eq!(printed("72"), "72");
     }
 }
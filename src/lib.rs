@@ -1,4 +1,6 @@
 pub mod data;
 pub mod parser;
 pub mod processor;
+pub mod performance;
+pub mod printer;
 
@@ -1,29 +1,23 @@
 mod data;
 mod parser;
 mod processor;
+mod printer;
 mod score;
 
 use clap::Parser;
-use parser::parse_score;
+use parser::parse_score_file;
 use processor::process_score;
 use score::generator::generate_score_def_yaml_from_score;
 
 mod cli;
 mod render;
-use cli::{Args, SubCommand, RenderArgs};
+use cli::{Args, SubCommand};
 
 fn main() {
     let args = Args::parse();
     match &args.subcommand {
         SubCommand::Generate { input, output } => {
-            let input = match std::fs::read_to_string(std::path::Path::new(input)) {
-                Ok(content) => content,
-                Err(e) => {
-                    eprintln!("Error reading file {}: {}", input, e);
-                    return;
-                }
-            };
-            match parse_score(&input) {
+            match parse_score_file(input) {
                 Ok(score) => {
                     let processed_score = process_score(score);
                     let formatted = format!("Parsed Score:\n{:#?}", processed_score);
@@ -51,14 +45,7 @@ fn main() {
             let vsc_input = "sample.vsc";
             let yaml_output = "score_workspace/score_def/score_def.yaml";
             let pvsc_output = "score_workspace/parsed_vsc.pvsc";
-            let input = match std::fs::read_to_string(std::path::Path::new(vsc_input)) {
-                Ok(content) => content,
-                Err(e) => {
-                    eprintln!("Error reading file {}: {}", vsc_input, e);
-                    return;
-                }
-            };
-            match parse_score(&input) {
+            match parse_score_file(vsc_input) {
                 Ok(score) => {
                     let processed_score = process_score(score);
 
@@ -98,6 +85,40 @@ fn main() {
                 }
             }
         }
+        SubCommand::Format { input, output } => {
+            // include・モチーフ・相対オクターブ入力は展開して書き出すので、元のファイルは上書きしない
+            let source = std::fs::read_to_string(input).unwrap_or_default();
+            let expanded = printer::expanded_syntax(&source);
+            let in_place = output.as_ref().is_some_and(|path| same_file(path, input));
+            if in_place && !expanded.is_empty() {
+                eprintln!("Refusing to format {} in place: the output would expand these lines", input);
+                for (line, name) in expanded {
+                    eprintln!("  {}:{}: {}", input, line + 1, name);
+                }
+                return;
+            }
+            match parse_score_file(input) {
+                Ok(score) => {
+                    let formatted = printer::print_score(&score);
+                    for (line, name) in expanded {
+                        eprintln!("Warning: {}:{}: {} is written out expanded", input, line + 1, name);
+                    }
+                    match output {
+                        Some(path) => match std::fs::write(path, &formatted) {
+                            Ok(()) => println!("Write file: {}", path),
+                            Err(e) => eprintln!("Error writing to file {}: {}", path, e),
+                        },
+                        None => print!("{}", formatted),
+                    }
+                }
+                Err(errs) => {
+                    eprintln!("Parse error(s):");
+                    for err in errs {
+                        eprintln!("  {}", err);
+                    }
+                }
+            }
+        }
         SubCommand::Render(render_args) => {
             use crate::render::input::load_score_def;
             use crate::render::backend::svg::render_svg;
@@ -125,3 +146,11 @@ fn main() {
         }
     }
 }
+
+/// 2つのパスが同じファイルを指すか（まだ存在しないファイルはパスの文字列で比べる）
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
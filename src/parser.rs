@@ -1,24 +1,54 @@
 use crate::data::{
-    Score, Measure, Beat, ScoreElement, Event, EventType, Subdivision, Chord, Pitch, Tie,
+    Score, Part, Lyric, Syllabic, Measure, MeasureRest, Voice, Beat, ScoreElement, Event, EventType, Subdivision, Chord, Pitch, Tie, NoteRef,
+    MeasureLength, MeasureMarks, Jump, GraceNote, GraceKind, NoteLetter, Articulation, TempoChange, KeyChange, DrumSound, DYNAMICS_LEVELS, CLEFS, INSTRUMENTS, KEYS, NOTEHEADS, MAX_PITCH_CENTS,
 };
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-/// Represents a parsing error with an optional line number.
+/// A half-open byte range `[start, end)` within a single source line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// 0-based byte offset of the first character.
+    pub start: usize,
+    /// 0-based byte offset just past the last character.
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start.min(other.start), end: self.end.max(other.end) }
+    }
+}
+
+/// Represents a parsing error with an optional line number and column range.
 #[derive(Debug, Clone)]
 pub struct ParseError {
     /// The error message.
     pub message: String,
     /// The 0-based line index where the error occurred, if available.
     pub line: Option<usize>,
+    /// The byte range within `line` that caused the error, if available.
+    pub span: Option<Span>,
+    /// The file the error came from, when parsing with `parse_score_file`.
+    pub file: Option<PathBuf>,
 }
 
 // Implement Display for easy printing of errors.
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(line) = self.line {
-            // Add 1 to line for 1-based display
-            write!(f, "Line {}: {}", line + 1, self.message)
-        } else {
-            write!(f, "{}", self.message)
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        match (self.line, self.span) {
+            // Add 1 to line/column for 1-based display
+            (Some(line), Some(span)) => write!(f, "Line {}, Col {}: {}", line + 1, span.start + 1, self.message),
+            (Some(line), None) => write!(f, "Line {}: {}", line + 1, self.message),
+            _ => write!(f, "{}", self.message),
         }
     }
 }
@@ -26,191 +56,1109 @@ impl std::fmt::Display for ParseError {
 // Implement standard Error trait.
 impl std::error::Error for ParseError {}
 
+/// ソース上の位置付きトークン
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+impl Token {
+    fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
 /// 1文字ずつ走査し、区切り文字 `[]{} ,` を独立したトークンとして抽出します。
-/// 空白はすべてスキップし、その他の文字は一続きのバッファとしてまとめます。
-fn tokenize(s: &str) -> Vec<String> {
+/// 空白はスキップし（`(...)` の中では1つの空白として残す）、その他の文字は一続きのバッファとしてまとめます。
+/// `base` は `s` の先頭が行内で何バイト目かを表し、各トークンの Span に加算されます。
+/// `relative` のときは、オクターブ指定のない音名の直後の `,` をオクターブ下げの記号として音名に含めます。
+fn tokenize(s: &str, base: usize, relative: bool) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut buf = String::new();
-    for ch in s.chars() {
+    let mut buf_span = Span::new(0, 0);
+    let mut paren_depth = 0;
+    for (i, ch) in s.char_indices() {
         match ch {
+            ',' if relative && is_octave_comma(&buf, &s[i..]) => {
+                buf_span.end = base + i + 1;
+                buf.push(ch);
+            }
             '[' | ']' | '{' | '}' | ',' => {
                 if !buf.is_empty() {
-                    tokens.push(buf.clone());
+                    tokens.push(Token { text: buf.clone(), span: buf_span });
                     buf.clear();
                 }
-                tokens.push(ch.to_string());
+                paren_depth = 0;
+                tokens.push(Token { text: ch.to_string(), span: Span::new(base + i, base + i + 1) });
             }
             c if c.is_whitespace() => {
-                // 空白は無視
+                // 空白は無視（前打音 `(D5 E5)` の区切りは残す）
+                if paren_depth > 0 && !buf.ends_with(' ') {
+                    buf.push(' ');
+                }
+            }
+            _ => {
+                match ch {
+                    '(' => paren_depth += 1,
+                    ')' => paren_depth -= 1,
+                    _ => {}
+                }
+                if buf.is_empty() {
+                    buf_span.start = base + i;
+                }
+                buf_span.end = base + i + ch.len_utf8();
+                buf.push(ch);
             }
-            _ => buf.push(ch),
         }
     }
     if !buf.is_empty() {
-        tokens.push(buf);
+        tokens.push(Token { text: buf, span: buf_span });
     }
     tokens
 }
 
+/// 相対オクターブ入力で、`rest` の先頭の `,` が `buf` の音名に付くオクターブ記号か。
+/// 連続する `,` の最後の1つは区切りとして扱う。ただし直後に奏法記号・タイ・重みが続く場合や、
+/// 閉じ括弧・入力の終わりが続く場合はすべてオクターブ記号とする（`[C, D,, E,]`）。
+fn is_octave_comma(buf: &str, rest: &str) -> bool {
+    let note = buf.rsplit(['(', ')', ' ']).next().unwrap_or("");
+    if !Pitch::is_relative_spelling(note) {
+        return false;
+    }
+    let after = rest.trim_start_matches(',');
+    let run = rest.len() - after.len();
+    let attached = after.starts_with(['\'', '.', '>', '!', '-', '*', '@'])
+        || after.trim_start().is_empty()
+        || after.trim_start().starts_with([']', '}', ')']);
+    run > 1 || attached
+}
+
+/// parse_tokens の結果。`weights[i]` は `elements[i]` の長さの重み（`*N`、省略時は 1）、
+/// `spans[i]` はそのソース上の範囲、`sources[i]` は先頭トークンの位置。
+/// モチーフの展開ではすべてのトークンが同じ範囲を持つので、トークンは位置で引く。
+struct ParsedElements {
+    elements: Vec<ScoreElement>,
+    weights: Vec<u32>,
+    spans: Vec<Span>,
+    sources: Vec<usize>,
+}
+
+impl ParsedElements {
+    /// 重み付きの要素があればその位置を返す（重みが使えない文脈のエラー報告用）
+    fn weighted_index(&self) -> Option<usize> {
+        self.weights.iter().position(|&w| w != 1)
+    }
+}
+
+/// tie の接続元を探すための文脈。
+/// 小節の先頭のタイは、同じ声部の前の小節の末尾から接続元を探す。
+struct TieContext<'a> {
+    measure: usize,
+    prev_measure: Option<(usize, &'a [ScoreElement])>,
+    /// 相対オクターブ入力のとき、小節内にまだ音がない場合の基準音（同じ声部の直前の音、または `#[Relative(...)]` の音）
+    relative: Option<&'a Pitch>,
+    /// 名前で書く打楽器の音（`kick`, `hh.open`）。組み込みと `#[Drum(...)]` で定義したもの
+    drums: &'a [DrumSound],
+}
+
+/// Subdivision を展開した葉要素（音符・休符・タイ・和音）を順に集める
+fn collect_leaves<'a>(elements: &'a [ScoreElement], out: &mut Vec<&'a ScoreElement>) {
+    for se in elements {
+        match se {
+            ScoreElement::Subdivision(sub) => collect_leaves(&sub.elements, out),
+            _ => out.push(se),
+        }
+    }
+}
+
+/// 直前の葉要素を新しい順に（小節番号付きで）返す
+fn recent_leaves<'a>(prev: &'a [ScoreElement], ctx: &TieContext<'a>) -> Vec<(usize, &'a ScoreElement)> {
+    let mut leaves = Vec::new();
+    collect_leaves(prev, &mut leaves);
+    let mut recent: Vec<_> = leaves.into_iter().rev().map(|l| (ctx.measure, l)).collect();
+    if let Some((number, elements)) = ctx.prev_measure {
+        let mut leaves = Vec::new();
+        collect_leaves(elements, &mut leaves);
+        recent.extend(leaves.into_iter().rev().map(|l| (number, l)));
+    }
+    recent
+}
+
+/// 相対オクターブ入力の基準音。小節内の直前の音（和音は最初の構成音）、なければ文脈の基準音
+fn relative_reference<'a>(prev: &'a [ScoreElement], ctx: &TieContext<'a>) -> Option<&'a Pitch> {
+    let mut leaves = Vec::new();
+    collect_leaves(prev, &mut leaves);
+    leaves.into_iter().rev().find_map(leaf_pitch).or(ctx.relative)
+}
+
+/// 葉要素の音高（休符・打楽器の音は None）
+fn leaf_pitch(leaf: &ScoreElement) -> Option<&Pitch> {
+    match leaf {
+        ScoreElement::Event(ev) => ev.pitch.as_ref().filter(|_| ev.unpitched.is_none()),
+        ScoreElement::Tie(tie) => tie.pitch.as_ref(),
+        ScoreElement::Chord(chord) => chord.events.first().and_then(|ev| ev.pitch.as_ref().filter(|_| ev.unpitched.is_none())),
+        ScoreElement::Subdivision(_) => None,
+    }
+}
+
+fn note_ref(measure: usize, id: Option<u64>) -> Option<NoteRef> {
+    id.map(|id| NoteRef { measure, id })
+}
+
+/// `C5-` のようにタイ記号の付いた直前の音（和音なら同じ高さの構成音）を探す
+fn find_dash_tie_origin(pitch_cents: Option<u16>, prev: &[ScoreElement], ctx: &TieContext) -> Option<NoteRef> {
+    let (measure, leaf) = recent_leaves(prev, ctx).into_iter().next()?;
+    let matches = |ev: &Event| ev.tie && ev.event_type == EventType::Note && ev.pitch_cents == pitch_cents;
+    match leaf {
+        ScoreElement::Event(ev) if matches(ev) => note_ref(measure, ev.id),
+        ScoreElement::Chord(chord) => chord.events.iter().find(|ev| matches(ev)).and_then(|ev| note_ref(measure, ev.id)),
+        _ => None,
+    }
+}
+
+/// `C5-` のように `-` でタイを付けた音（和音なら構成音）のうち、拍 `beat` の中または直前で
+/// 次の要素に続いていないものがあればエラーにする（`prev` はこの拍より前の要素）
+fn check_dash_ties(beat: &Beat, prev: &[ScoreElement], ctx: &TieContext) -> Result<(), String> {
+    let mut leaves: Vec<(usize, &ScoreElement)> = recent_leaves(prev, ctx).into_iter().take(1).collect();
+    let mut inner = Vec::new();
+    collect_leaves(&beat.elements, &mut inner);
+    leaves.extend(inner.into_iter().map(|leaf| (ctx.measure, leaf)));
+    for pair in leaves.windows(2) {
+        let ((measure, leaf), (_, next)) = (pair[0], pair[1]);
+        let continues = |ev: &Event| {
+            let origin = note_ref(measure, ev.id);
+            match next {
+                ScoreElement::Event(next) => next.tied_from == origin,
+                ScoreElement::Chord(chord) => chord.events.iter().any(|m| m.tied_from == origin),
+                _ => true,
+            }
+        };
+        if let Some(ev) = dash_tied(leaf).into_iter().find(|ev| !continues(ev)) {
+            return Err(format!(
+                "Tie `{}-` is not followed by a note of the same pitch (write the same note next, or `t(...)` for a pitch slide)",
+                event_name(ev)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 葉要素のうち `-` でタイを付けた音（和音なら構成音）
+fn dash_tied(leaf: &ScoreElement) -> Vec<&Event> {
+    match leaf {
+        ScoreElement::Event(ev) if ev.tie => vec![ev],
+        ScoreElement::Chord(chord) => chord.events.iter().filter(|ev| ev.tie).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// エラーメッセージ用の音の書き方（音名、または打楽器の音の名前）
+fn event_name(ev: &Event) -> String {
+    match (&ev.unpitched, &ev.pitch) {
+        (Some(unpitched), _) => unpitched.name.clone(),
+        (None, Some(pitch)) => pitch.to_string(),
+        (None, None) => "r".to_string(),
+    }
+}
+
+/// 声部の最後の音に `-` でタイを付けていればエラーにする（続く音がない）。
+/// `last_measures` は譜表・声部ごとの最後の小節番号とその行
+fn check_trailing_dash_ties(measures: &[Measure], last_measures: &HashMap<(usize, usize), (usize, usize)>) -> Vec<ParseError> {
+    let mut voices: Vec<_> = last_measures.iter().collect();
+    voices.sort();
+    voices.into_iter().filter_map(|(&(staff, voice), &(number, line))| {
+        let beats = &measures.iter()
+            .find(|m| m.number == number)?
+            .voices.iter()
+            .find(|v| v.staff == staff && v.number == voice)?
+            .beats;
+        let mut leaves = Vec::new();
+        beats.iter().for_each(|b| collect_leaves(&b.elements, &mut leaves));
+        let ev = dash_tied(leaves.last()?).into_iter().next()?;
+        Some(ParseError {
+            message: format!(
+                "Tie `{}-` at the end of staff {} voice {} has no following note (Measure {})",
+                event_name(ev), staff, voice, number
+            ),
+            line: Some(line),
+            span: None,
+            file: None,
+        })
+    }).collect()
+}
+
+/// 和音内の `t` の接続元となる直前の和音を探す（間にあるタイは飛ばす）
+fn find_chord_tie_origin<'a>(prev: &'a [ScoreElement], ctx: &TieContext<'a>) -> Option<(usize, &'a Chord)> {
+    match recent_leaves(prev, ctx).into_iter().find(|(_, l)| !matches!(l, ScoreElement::Tie(_)))? {
+        (measure, ScoreElement::Chord(chord)) => Some((measure, chord)),
+        _ => None,
+    }
+}
+
+/// 和音の構成音を組み立てる。構成音の `t` は直前の和音の同じ位置の音を引き継ぎ、
+/// `-` 付きの音と同じ高さの構成音はその音からのタイとして結ぶ。
+/// `t+50c` のような相対的なスライドは、前の和音の同じ位置の構成音から曲げる（`tokens` は和音の中身）。
+/// 使えない構成音はすべて `errors` に報告して飛ばす。
+fn build_chord_members(
+    members: Vec<ScoreElement>,
+    spans: &[Span],
+    sources: &[usize],
+    tokens: &[Token],
+    prev: &[ScoreElement],
+    ctx: &TieContext,
+    errors: &mut Vec<ParseError>,
+) -> Vec<Event> {
+    let origin_chord = find_chord_tie_origin(prev, ctx);
+    let mut events = Vec::new();
+    let mut error = |message: String, i: usize| errors.push(ParseError {
+        message,
+        line: None,
+        span: Some(spans[i]),
+        file: None,
+    });
+    for (i, se) in members.into_iter().enumerate() {
+        match se {
+            ScoreElement::Event(ev) if !ev.grace_notes.is_empty() => {
+                error("Grace notes cannot be attached to a chord member".into(), i);
+            }
+            ScoreElement::Event(mut ev) => {
+                ev.tied_from = if ev.event_type == EventType::Note {
+                    find_dash_tie_origin(ev.pitch_cents, prev, ctx)
+                } else {
+                    None
+                };
+                events.push(ev);
+            }
+            ScoreElement::Tie(tie) => {
+                let Some((measure, origin)) = origin_chord
+                    .and_then(|(measure, chord)| chord.events.get(i).map(|ev| (measure, ev)))
+                else {
+                    error(format!("Chord tie `t` at position {} has no matching note in the previous chord", i + 1), i);
+                    continue;
+                };
+                // `t(E5)` のように音高を変える場合はタイの音高を使う。
+                // 構成音は順に解析されるので、`t+50c` は同じ位置の構成音を基準に解決し直す
+                let (pitch, pitch_cents) = if tie.slide {
+                    let target = tokens.get(sources[i])
+                        .and_then(|t| split_weight(t).ok())
+                        .and_then(|(t, _)| split_anchor(&t).ok())
+                        .and_then(|(t, _)| tie_target(t.as_str()).map(str::to_string));
+                    match target {
+                        Some(target) => match resolve_tie_target(&target, origin.pitch.clone(), origin.pitch_cents, spans[i]) {
+                            Ok(resolved) => resolved,
+                            Err(e) => {
+                                error(e.message, i);
+                                continue;
+                            }
+                        },
+                        None => (tie.pitch, tie.pitch_cents),
+                    }
+                } else {
+                    (origin.pitch.clone(), origin.pitch_cents)
+                };
+                events.push(Event {
+                    id: tie.id,
+                    anchored: tie.anchored,
+                    event_type: EventType::Note,
+                    pitch,
+                    pitch_cents,
+                    tie: false,
+                    tied_from: note_ref(measure, origin.id),
+                    slide: tie.slide,
+                    grace_notes: Vec::new(),
+                    articulations: Vec::new(),
+                    dynamic: None,
+                    unpitched: origin.unpitched.clone().filter(|_| !tie.slide),
+                    duration: origin.duration,
+                });
+            }
+            _ => error("Chord may contain only simple events".into(), i),
+        }
+    }
+    events
+}
+
 /// トークン列を再帰的にパースして ScoreElement のベクタを返します。
 /// `outer_prev` はこのレベルの前にあった要素（tie の解決に利用）。
+/// 誤りのあるトークンや括弧は `errors` に報告して読み飛ばし、残りの解析を続けます。
 use crate::data::IdGenerator;
 
 fn parse_tokens(
-    tokens: &[String],
+    tokens: &[Token],
     outer_prev: &[ScoreElement],
+    ctx: &TieContext,
     id_gen: &mut IdGenerator,
-) -> Result<Vec<ScoreElement>, ParseError> {
+    errors: &mut Vec<ParseError>,
+) -> ParsedElements {
     let mut elems = Vec::new();
+    let mut weights = Vec::new();
+    let mut spans = Vec::new();
+    let mut sources = Vec::new();
     let mut idx = 0;
     while idx < tokens.len() {
         match tokens[idx].as_str() {
             "," => {
                 idx += 1;
             }
-            "[" => {
-                let mut depth = 1;
+            open @ ("[" | "{") => {
+                let close = if open == "[" { "]" } else { "}" };
                 let start = idx + 1;
-                let mut end = start;
-                while end < tokens.len() && depth > 0 {
-                    match tokens[end].as_str() {
-                        "[" => depth += 1,
-                        "]" => depth -= 1,
-                        _ => {}
+                // 閉じ括弧がなければ行末までを中身として読み、中の誤りも報告する
+                let (inner_end, end) = match find_group_end(tokens, idx, open, close) {
+                    Some(close_idx) => (close_idx, close_idx + 1),
+                    None => {
+                        errors.push(ParseError {
+                            message: format!("Unmatched '{}' (missing '{}')", open, close),
+                            line: None,
+                            span: Some(tokens[idx].span),
+                            file: None,
+                        });
+                        (tokens.len(), tokens.len())
                     }
-                    end += 1;
-                }
-                if depth != 0 {
-                    return Err(ParseError { message: "Unmatched '['".into(), line: None });
-                }
-                let inner = &tokens[start..end - 1];
+                };
+                let inner = &tokens[start..inner_end];
                 let mut combined = outer_prev.to_vec();
                 combined.extend(elems.clone());
-                let sub_elems = parse_tokens(inner, &combined, id_gen)?;
-                let base_division = sub_elems.len() as u32;
-                elems.push(ScoreElement::Subdivision(Subdivision {
-                    elements: sub_elems,
-                    base_division,
-                }));
-                idx = end;
-            }
-            "{" => {
-                let mut depth = 1;
-                let start = idx + 1;
-                let mut end = start;
-                while end < tokens.len() && depth > 0 {
-                    match tokens[end].as_str() {
-                        "{" => depth += 1,
-                        "}" => depth -= 1,
-                        _ => {}
+                let group = parse_tokens(inner, &combined, ctx, id_gen, errors);
+                if open == "[" {
+                    let base_division = group.weights.iter().sum();
+                    elems.push(ScoreElement::Subdivision(Subdivision {
+                        elements: group.elements,
+                        weights: group.weights,
+                        base_division,
+                    }));
+                } else {
+                    if let Some(i) = group.weighted_index() {
+                        errors.push(ParseError {
+                            message: "Length weight `*N` cannot be used inside a chord".into(),
+                            line: None,
+                            span: Some(group.spans[i]),
+                            file: None,
+                        });
                     }
-                    end += 1;
+                    let events = build_chord_members(group.elements, &group.spans, &group.sources, inner, &combined, ctx, errors);
+                    elems.push(ScoreElement::Chord(Chord { id: Some(id_gen.next_id()), anchored: false, events }));
                 }
-                if depth != 0 {
-                    return Err(ParseError { message: "Unmatched '{'".into(), line: None });
-                }
-                let inner = &tokens[start..end - 1];
-                let mut combined = outer_prev.to_vec();
-                combined.extend(elems.clone());
-                let chord_elems = parse_tokens(inner, &combined, id_gen)?;
-                let mut events = Vec::new();
-                for se in chord_elems {
-                    if let ScoreElement::Event(ev) = se {
-                        events.push(ev);
-                    } else {
-                        return Err(ParseError { message: "Chord may contain only simple events".into(), line: None });
+                let (weight, anchor, weight_span) = parse_group_suffix(tokens.get(end)).unwrap_or_else(|e| {
+                    errors.push(e);
+                    (1, None, tokens.get(end).map(|t| t.span))
+                });
+                if let (Some(anchor), Some(group), Some(span)) = (anchor, elems.last_mut(), weight_span) {
+                    if let Err(e) = anchor_element(group, anchor, span, ctx, id_gen) {
+                        errors.push(e);
                     }
                 }
-                elems.push(ScoreElement::Chord(Chord { id: Some(id_gen.next_id()), events }));
-                idx = end;
+                weights.push(weight);
+                sources.push(idx);
+                spans.push(tokens[idx].span.to(weight_span.unwrap_or(tokens[end - 1].span)));
+                idx = if weight_span.is_some() { end + 1 } else { end };
             }
-            tok => {
+            close @ ("]" | "}") => {
+                let open = if close == "]" { "[" } else { "{" };
+                errors.push(ParseError {
+                    message: format!("Unmatched '{}' (no opening '{}')", close, open),
+                    line: None,
+                    span: Some(tokens[idx].span),
+                    file: None,
+                });
+                idx += 1;
+            }
+            _ => {
                 let mut combined = outer_prev.to_vec();
                 combined.extend(elems.clone());
-                let se = parse_token(tok, &combined, id_gen)?;
-                elems.push(se);
+                let parsed = split_weight(&tokens[idx]).and_then(|(token, weight)| {
+                    let (token, anchor) = split_anchor(&token)?;
+                    let mut se = parse_token(&token, &combined, ctx, id_gen)?;
+                    if let Some(anchor) = anchor {
+                        anchor_element(&mut se, anchor, tokens[idx].span, ctx, id_gen)?;
+                    }
+                    Ok((se, weight))
+                });
+                match parsed {
+                    Ok((se, weight)) => {
+                        elems.push(se);
+                        weights.push(weight);
+                        spans.push(tokens[idx].span);
+                        sources.push(idx);
+                    }
+                    Err(e) => errors.push(e),
+                }
                 idx += 1;
             }
         }
     }
-    Ok(elems)
+    ParsedElements { elements: elems, weights, spans, sources }
+}
+
+/// `tokens[open_idx]` の開き括弧に対応する閉じ括弧の位置
+fn find_group_end(tokens: &[Token], open_idx: usize, open: &str, close: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open_idx) {
+        if token.as_str() == open {
+            depth += 1;
+        } else if token.as_str() == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// 重みの数値部分を解釈する（1以上の整数）
+fn parse_weight(text: &str, span: Span) -> Result<u32, ParseError> {
+    match text.parse::<u32>() {
+        Ok(w) if w > 0 => Ok(w),
+        _ => Err(ParseError {
+            message: format!("Invalid length weight `*{}`: expected a positive integer", text),
+            line: None,
+            span: Some(span),
+            file: None,
+        }),
+    }
+}
+
+/// 音符トークン末尾の `*N` を切り出し、(重みを除いたトークン, 重み) を返す
+fn split_weight(token: &Token) -> Result<(Token, u32), ParseError> {
+    match token.text.rfind('*') {
+        Some(pos) => {
+            let weight_span = Span::new(token.span.start + pos, token.span.end);
+            let weight = parse_weight(&token.text[pos + 1..], weight_span)?;
+            let core = Token {
+                text: token.text[..pos].to_string(),
+                span: Span::new(token.span.start, token.span.start + pos),
+            };
+            Ok((core, weight))
+        }
+        None => Ok((token.clone(), 1)),
+    }
+}
+
+/// `]` や `}` の直後に続く `@N`（IDの固定）と `*N`（重み）のトークンを解釈し、
+/// (重み, 固定するID, トークンの範囲) を返す
+fn parse_group_suffix(next: Option<&Token>) -> Result<(u32, Option<u64>, Option<Span>), ParseError> {
+    let Some(tok) = next.filter(|t| t.text.starts_with(['@', '*'])) else {
+        return Ok((1, None, None));
+    };
+    let (anchor, weight) = match tok.text.find('*') {
+        Some(pos) => (&tok.text[..pos], Some(&tok.text[pos + 1..])),
+        None => (tok.text.as_str(), None),
+    };
+    let anchor = match anchor.strip_prefix('@') {
+        Some(n) => Some(parse_anchor(n, tok.span)?),
+        None => None,
+    };
+    let weight = weight.map_or(Ok(1), |w| parse_weight(w, tok.span))?;
+    Ok((weight, anchor, Some(tok.span)))
+}
+
+/// 音符トークン末尾の `@N` を切り出し、(IDの指定を除いたトークン, 固定するID) を返す
+fn split_anchor(token: &Token) -> Result<(Token, Option<u64>), ParseError> {
+    match token.text.rfind('@') {
+        Some(pos) => {
+            let anchor_span = Span::new(token.span.start + pos, token.span.end);
+            let anchor = parse_anchor(&token.text[pos + 1..], anchor_span)?;
+            let core = Token {
+                text: token.text[..pos].to_string(),
+                span: Span::new(token.span.start, token.span.start + pos),
+            };
+            Ok((core, Some(anchor)))
+        }
+        None => Ok((token.clone(), None)),
+    }
+}
+
+/// IDの固定 `@N` の数値部分を解釈する（1以上の整数）
+fn parse_anchor(text: &str, span: Span) -> Result<u64, ParseError> {
+    match text.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(ParseError {
+            message: format!("Invalid id anchor `@{}`: expected a positive integer", text),
+            line: None,
+            span: Some(span),
+            file: None,
+        }),
+    }
+}
+
+/// 要素のIDを `@N` の番号に固定する（解析の最後に build_part で置き換える）
+fn anchor_element(
+    se: &mut ScoreElement,
+    anchor: u64,
+    span: Span,
+    ctx: &TieContext,
+    id_gen: &mut IdGenerator,
+) -> Result<(), ParseError> {
+    let error = |message: String| ParseError { message, line: None, span: Some(span), file: None };
+    let (id, anchored) = match se {
+        ScoreElement::Event(ev) => (ev.id, &mut ev.anchored),
+        ScoreElement::Tie(tie) => (tie.id, &mut tie.anchored),
+        ScoreElement::Chord(chord) => (chord.id, &mut chord.anchored),
+        ScoreElement::Subdivision(_) => return Err(error("An id anchor `@N` cannot be attached to '[...]'".to_string())),
+    };
+    let id = id.ok_or_else(|| error("Element has no id to anchor".to_string()))?;
+    id_gen.anchor(id, anchor).map_err(|e| error(format!("{} (Measure {})", e, ctx.measure)))?;
+    *anchored = true;
+    Ok(())
 }
 
 /// 単一トークンの解釈。tie("t"), rest("r"), note などを処理。
-fn parse_token(token: &str, prev: &[ScoreElement], id_gen: &mut IdGenerator) -> Result<ScoreElement, ParseError> {
+fn parse_token(
+    token: &Token,
+    prev: &[ScoreElement],
+    ctx: &TieContext,
+    id_gen: &mut IdGenerator,
+) -> Result<ScoreElement, ParseError> {
+    let span = token.span;
+    let token = token.as_str();
     if token == "r" {
         return Ok(ScoreElement::Event(Event {
             id: Some(id_gen.next_id()),
+            anchored: false,
             event_type: EventType::Rest,
             pitch: None,
             pitch_cents: None,
             tie: false,
+            tied_from: None,
+            slide: false,
+            grace_notes: Vec::new(),
+            articulations: Vec::new(),
+            dynamic: None,
+            unpitched: None,
             duration: num_rational::Ratio::from_integer(1),
         }));
     }
-    if token == "t" {
-        // 直前の音符・タイ・和音からpitch/durationを取得
+    if token == "R" {
+        return Err(ParseError {
+            message: "Whole-measure rest `R` must be the only content of the measure (use `r` for a rest of one beat)".to_string(),
+            line: None,
+            span: Some(span),
+            file: None,
+        });
+    }
+    // 前打音（`(D5)C5`、`(D5 E5)C5`、短前打音は `(/D5)C5`）は後続の音符に付ける
+    if let Some(rest) = token.strip_prefix('(') {
+        let error = |message: &str| ParseError { message: message.to_string(), line: None, span: Some(span), file: None };
+        let (grace, main) = rest.split_once(')').ok_or_else(|| error("Unclosed '(' in grace notes"))?;
+        let grace_notes = parse_grace_notes(grace, span, ctx.relative.and(relative_reference(prev, ctx)), id_gen)?;
+        let main = Token { text: main.to_string(), span };
+        return match parse_token(&main, prev, ctx, id_gen)? {
+            ScoreElement::Event(mut ev) if ev.event_type == EventType::Note => {
+                ev.grace_notes = grace_notes;
+                Ok(ScoreElement::Event(ev))
+            }
+            _ => Err(error("Grace notes must be followed by a note")),
+        };
+    }
+    if let Some(target) = tie_target(token) {
+        // 直前の音符・タイ・和音からpitch/durationを取得（休符の後のタイは接続元なし）
         let mut last_pitch = None;
         let mut last_pitch_cents = None;
         let mut last_duration = num_rational::Ratio::from_integer(1);
-        for se in prev.iter().rev() {
-            match se {
-                ScoreElement::Event(ev) if ev.event_type == EventType::Note => {
+        let mut tied_from = None;
+        match recent_leaves(prev, ctx).into_iter().next() {
+            Some((measure, ScoreElement::Event(ev))) if ev.event_type == EventType::Note => {
+                last_pitch = ev.pitch.clone();
+                last_pitch_cents = ev.pitch_cents;
+                last_duration = ev.duration;
+                tied_from = note_ref(measure, ev.id);
+            }
+            Some((measure, ScoreElement::Tie(tie))) => {
+                last_pitch = tie.pitch.clone();
+                last_pitch_cents = tie.pitch_cents;
+                last_duration = tie.duration;
+                tied_from = note_ref(measure, tie.id);
+            }
+            Some((measure, ScoreElement::Chord(chord))) => {
+                if let Some(ev) = chord.events.first() {
                     last_pitch = ev.pitch.clone();
                     last_pitch_cents = ev.pitch_cents;
                     last_duration = ev.duration;
-                    break;
-                }
-                ScoreElement::Tie(tie) => {
-                    last_pitch = tie.pitch.clone();
-                    last_pitch_cents = tie.pitch_cents;
-                    last_duration = tie.duration;
-                    break;
-                }
-                ScoreElement::Chord(chord) => {
-                    if let Some(ev) = chord.events.first() {
-                        last_pitch = ev.pitch.clone();
-                        last_pitch_cents = ev.pitch_cents;
-                        last_duration = ev.duration;
-                        break;
-                    }
                 }
-                _ => {}
+                tied_from = note_ref(measure, chord.id);
             }
+            _ => {}
+        }
+        // 音高を変えるタイ（接続元からのピッチスライド）
+        let slide = !target.is_empty();
+        if slide {
+            let (pitch, pitch_cents) = resolve_tie_target(target, last_pitch, last_pitch_cents, span)?;
+            last_pitch = pitch;
+            last_pitch_cents = pitch_cents;
         }
         return Ok(ScoreElement::Tie(Tie {
             id: Some(id_gen.next_id()),
+            anchored: false,
             pitch: last_pitch,
             pitch_cents: last_pitch_cents,
+            tied_from,
+            slide,
             duration: last_duration,
         }));
     }
+    // 奏法・強弱の接尾辞（`C5.stacc`、`E5>`、`G5!ff`）。タイの `-` は最後に書く
+    // 打楽器の名前は `.` を含むことがあるので（`hh.open.stacc`）、先に名前を切り出す
+    let drum = find_drum(token, ctx.drums);
+    let suffixes = drum.map_or(token, |d| &token[d.name.len()..]);
+    let (core, articulations, dynamic) = split_note_suffixes(suffixes.trim_end_matches('-'))
+        .map_err(|message| ParseError { message, line: None, span: Some(span), file: None })?;
     let tie_flag = token.ends_with('-');
-    let core = token.trim_end_matches('-');
-    let pitch = core.parse::<Pitch>()
-        .map_err(|e| ParseError {
-            message: format!("Invalid pitch `{}`: {}", core, e),
-            line: None,
-        })?;
-    let pitch_cents = match pitch.to_midi_number() {
-        Ok(n) => Some((n as u16) * 100),
-        Err(_) => None,
+    let (pitch, pitch_cents) = match drum {
+        Some(drum) => (Pitch::Midi(drum.key), Some(drum.key as u16 * 100)),
+        None => parse_note_pitch(core, ctx.relative.and(relative_reference(prev, ctx)))
+            .map_err(|e| ParseError {
+                message: format!(
+                    "Invalid pitch `{}`: {}{}",
+                    core, e,
+                    hint(pitch_suggestion(core, relative_reference(prev, ctx))
+                        .or_else(|| did_you_mean(core, ctx.drums.iter().map(|d| d.name.as_str())).map(str::to_string))),
+                ),
+                line: None,
+                span: Some(span),
+                file: None,
+            })?,
     };
     Ok(ScoreElement::Event(Event {
         id: Some(id_gen.next_id()),
+        anchored: false,
         event_type: EventType::Note,
         pitch: Some(pitch),
         pitch_cents,
         tie: tie_flag,
+        tied_from: find_dash_tie_origin(pitch_cents, prev, ctx),
+        slide: false,
+        grace_notes: Vec::new(),
+        articulations,
+        dynamic,
+        unpitched: drum.map(DrumSound::unpitched),
         duration: num_rational::Ratio::from_integer(1),
     }))
 }
 
+/// トークンの先頭にある打楽器の名前（最も長く一致するもの）。名前の後は接尾辞かタイの `-` だけを書ける
+fn find_drum<'a>(token: &str, drums: &'a [DrumSound]) -> Option<&'a DrumSound> {
+    drums.iter()
+        .filter(|d| {
+            token.strip_prefix(d.name.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '>', '!', '-']))
+        })
+        .max_by_key(|d| d.name.len())
+}
+
+/// 音符トークンを音高部分と接尾辞（`.stacc` などの奏法、`>`、`!ff` などの強弱）に分ける
+fn split_note_suffixes(token: &str) -> Result<(&str, Vec<Articulation>, Option<String>), String> {
+    const MARKERS: [char; 3] = ['.', '>', '!'];
+    let (core, mut rest) = token.split_at(token.find(MARKERS).unwrap_or(token.len()));
+    let mut articulations = Vec::new();
+    let mut dynamic = None;
+    while let Some(marker) = rest.chars().next() {
+        let body = &rest[1..];
+        let name_len = if marker == '>' { 0 } else { body.find(MARKERS).unwrap_or(body.len()) };
+        let name = &body[..name_len];
+        rest = &body[name_len..];
+        match marker {
+            '!' => {
+                if !DYNAMICS_LEVELS.contains(&name) {
+                    return Err(match did_you_mean(name, DYNAMICS_LEVELS.iter().copied()) {
+                        Some(level) => format!("Unknown dynamic `!{}`{}", name, hint(Some(format!("!{}", level)))),
+                        None => format!("Unknown dynamic `!{}` (expected one of {})", name, DYNAMICS_LEVELS.join(", ")),
+                    });
+                }
+                if dynamic.is_some() {
+                    return Err(format!("Note `{}` has more than one dynamic", token));
+                }
+                dynamic = Some(name.to_uppercase());
+            }
+            _ => {
+                let articulation = if marker == '>' {
+                    Articulation::Accent
+                } else {
+                    Articulation::from_spelling(name).ok_or_else(|| {
+                        let spellings = Articulation::SPELLINGS.iter().map(|(spelling, _)| *spelling);
+                        let suggestion = did_you_mean(name, spellings).map(|spelling| format!(".{}", spelling));
+                        format!("Unknown articulation `.{}`{}", name, hint(suggestion))
+                    })?
+                };
+                if articulations.contains(&articulation) {
+                    return Err(format!("Articulation `{}` is written twice on `{}`", articulation.name(), token));
+                }
+                articulations.push(articulation);
+            }
+        }
+    }
+    Ok((core, articulations, dynamic))
+}
+
+/// 音符の音高を読む。相対オクターブ入力（`reference` あり）では、オクターブのない音名を基準音の近くに置く
+fn parse_note_pitch(text: &str, reference: Option<&Pitch>) -> Result<(Pitch, Option<u16>), String> {
+    match reference {
+        Some(reference) if Pitch::is_relative_spelling(text) => {
+            let pitch = Pitch::parse_relative(text, reference)?;
+            let cents = pitch.cents().ok();
+            Ok((pitch, cents))
+        }
+        _ => Pitch::parse_with_cents(text),
+    }
+}
+
+/// 読めなかった音高の書き直し案（`H5` → `B5`、`T` → `t`、`R` → `r`、`Cs5` → `C#5`、オクターブ抜け → 直前の音のオクターブ）
+fn pitch_suggestion(text: &str, reference: Option<&Pitch>) -> Option<String> {
+    let mut chars = text.chars();
+    let first = chars.next()?;
+    let rest = chars.as_str();
+    let candidates = match first {
+        'T' => vec![format!("t{}", rest)],
+        'H' | 'h' => vec![format!("B{}", rest)],
+        _ if Pitch::is_relative_spelling(text) => {
+            let octave = match reference {
+                Some(Pitch::NoteName { octave, .. }) => *octave,
+                Some(Pitch::Midi(n)) => *n as i32 / 12 - 1,
+                None => 4,
+            };
+            vec![format!("{}{}", text, octave)]
+        }
+        _ => vec![format!("{}{}", first, rest.replacen('s', "#", 1))],
+    };
+    candidates.into_iter().find(|c| {
+        c != text && (c == "r" || tie_target(c).is_some() || Pitch::parse_with_cents(c).is_ok())
+    })
+}
+
+/// `word` に綴りの近い候補（編集距離が最小で、候補の長さの半分以下のもの）
+fn did_you_mean<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|&(d, c)| d > 0 && d <= 2 && d <= c.chars().count() / 2)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+/// 文字単位のレーベンシュタイン距離
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (prev_diag + usize::from(ca != cb)).min(row[j] + 1).min(row[j + 1] + 1);
+            prev_diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// メッセージ末尾に付ける書き直し案
+fn hint(suggestion: Option<impl std::fmt::Display>) -> String {
+    suggestion.map_or(String::new(), |s| format!(" (did you mean `{}`?)", s))
+}
+
+/// `(...)` 内の前打音を読む。先頭の `/` は短前打音、音は空白で区切る。
+/// 相対オクターブ入力では、最初の前打音は `reference`、以降は直前の前打音を基準にする。
+fn parse_grace_notes(
+    spec: &str,
+    span: Span,
+    reference: Option<&Pitch>,
+    id_gen: &mut IdGenerator,
+) -> Result<Vec<GraceNote>, ParseError> {
+    let error = |message: String| ParseError { message, line: None, span: Some(span), file: None };
+    let (kind, notes) = match spec.trim().strip_prefix('/') {
+        Some(notes) => (GraceKind::Acciaccatura, notes),
+        None => (GraceKind::Appoggiatura, spec),
+    };
+    if notes.trim().is_empty() {
+        return Err(error("Grace notes '()' must contain at least one pitch".to_string()));
+    }
+    let mut reference = reference.cloned();
+    notes.split_whitespace().map(|note| {
+        let (pitch, pitch_cents) = parse_note_pitch(note, reference.as_ref())
+            .map_err(|e| error(format!("Invalid grace note pitch `{}`: {}{}", note, e, hint(pitch_suggestion(note, reference.as_ref())))))?;
+        if reference.is_some() {
+            reference = Some(pitch.clone());
+        }
+        Ok(GraceNote { id: Some(id_gen.next_id()), kind, pitch, pitch_cents })
+    }).collect()
+}
+
+/// タイトークンなら、`t` に続く目標音高の指定部分（`(C#5)` や `+50c`、無指定なら空文字列）を返す
+fn tie_target(token: &str) -> Option<&str> {
+    let rest = token.strip_prefix('t')?;
+    let is_target = rest.is_empty()
+        || (rest.starts_with('(') && rest.ends_with(')'))
+        || ((rest.starts_with('+') || rest.starts_with('-')) && rest.ends_with('c'));
+    is_target.then_some(rest)
+}
+
+/// `t(C#5)` の絶対音高、または `t+50c` の接続元からの相対セントを解決する
+fn resolve_tie_target(
+    target: &str,
+    origin_pitch: Option<Pitch>,
+    origin_cents: Option<u16>,
+    span: Span,
+) -> Result<(Option<Pitch>, Option<u16>), ParseError> {
+    let error = |message: String| ParseError { message, line: None, span: Some(span), file: None };
+    if let Some(inner) = target.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        let inner = inner.trim();
+        let (pitch, cents) = Pitch::parse_with_cents(inner)
+            .map_err(|e| error(format!("Invalid tie pitch `{}`: {}", inner, e)))?;
+        return Ok((Some(pitch), cents));
+    }
+    let deviation: i32 = target.strip_suffix('c').and_then(|t| t.parse().ok())
+        .ok_or_else(|| error(format!("Invalid tie cents offset `{}`", target)))?;
+    let origin = origin_cents
+        .ok_or_else(|| error(format!("Tie `t{}` has no preceding note to bend from", target)))?;
+    let cents = origin as i32 + deviation;
+    if !(0..=crate::data::MAX_PITCH_CENTS).contains(&cents) {
+        return Err(error(format!("Tie `t{}` is out of range (0c to {}c)", target, crate::data::MAX_PITCH_CENTS)));
+    }
+    Ok((origin_pitch, Some(cents as u16)))
+}
+
+/// 1拍分のトークン列を Beat に変換する。拍の直下では長さの重みは使えない。
+fn parse_beat(
+    tokens: &[Token],
+    prev: &[ScoreElement],
+    ctx: &TieContext,
+    id_gen: &mut IdGenerator,
+) -> Result<Beat, Vec<ParseError>> {
+    let mut errors = Vec::new();
+    let parsed = parse_tokens(tokens, prev, ctx, id_gen, &mut errors);
+    if let Some(i) = parsed.weighted_index() {
+        errors.push(ParseError {
+            message: "Length weight `*N` is only allowed inside '[...]'".into(),
+            line: None,
+            span: Some(parsed.spans[i]),
+            file: None,
+        });
+    }
+    if errors.is_empty() {
+        Ok(Beat { elements: parsed.elements, duration: 0.0, units: 1 })
+    } else {
+        Err(errors)
+    }
+}
+
+/// 括弧 `[]`・`{}` がすべて正しく対応しているか
+fn brackets_balanced(tokens: &[Token]) -> bool {
+    let mut stack = Vec::new();
+    for token in tokens {
+        match token.as_str() {
+            "[" => stack.push("]"),
+            "{" => stack.push("}"),
+            close @ ("]" | "}") if stack.pop() != Some(close) => return false,
+            _ => {}
+        }
+    }
+    stack.is_empty()
+}
+
+/// 深さ0の `,` でトークン列を拍ごとに分ける。
+/// 対応のない閉じ括弧は深さを変えず、最後まで閉じない開き括弧はそこで拍を区切って
+/// 残りを改めて拍に分ける（誤りは parse_tokens が報告する）。
+fn split_beats(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut beats = Vec::new();
+    let mut beat_tokens = Vec::new();
+    // 現在の拍の中で閉じていない開き括弧の位置
+    let mut open = Vec::new();
+    for token in tokens {
+        match token.as_str() {
+            "[" | "{" => { open.push(beat_tokens.len()); beat_tokens.push(token); }
+            "]" | "}" => { open.pop(); beat_tokens.push(token); }
+            "," if open.is_empty() => {
+                if !beat_tokens.is_empty() {
+                    beats.push(std::mem::take(&mut beat_tokens));
+                }
+            }
+            _ => { beat_tokens.push(token); }
+        }
+    }
+    if let Some(&unclosed) = open.first() {
+        let rest = beat_tokens.split_off(unclosed + 1);
+        beats.push(beat_tokens);
+        beats.extend(split_beats(rest));
+    } else if !beat_tokens.is_empty() {
+        beats.push(beat_tokens);
+    }
+    beats
+}
+
+/// `let name = [...]` で定義されたモチーフ。本体は小節の `[...]` と同じく拍の列として書く。
+struct Motif {
+    /// 拍ごとのトークン
+    beats: Vec<Vec<Token>>,
+    /// 定義行（0始まり）
+    line: usize,
+    /// メッセージ用の定義位置（"line 3" など）
+    location: String,
+    /// 定義行内の本体の範囲
+    span: Span,
+    /// 定義自体にエラーがあった場合は false（使用箇所でもエラーにする）
+    valid: bool,
+}
+
+/// モチーフ名として使える識別子か（小文字で始まり、音名・`t`・`r` と紛らわしくないもの）
+fn is_motif_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && name != "t"
+        && name != "r"
+        && Pitch::parse_with_cents(name).is_err()
+}
+
+/// `let` に続く `name = [...]` を読み、本体を単独で解析してエラーがないか確かめる。
+/// 本体にエラーがあっても、使用箇所で未定義扱いにならないよう `valid: false` で返す。
+fn parse_motif_definition(
+    def: &str,
+    line_content: &str,
+    line_idx: usize,
+    sources: &SourceMap,
+    motifs: &HashMap<String, Motif>,
+    ctx: &TieContext,
+    errors: &mut Vec<ParseError>,
+) -> Option<(String, Motif)> {
+    let mut error = |message: String, span: Span| {
+        errors.push(ParseError { message, line: Some(line_idx), span: Some(span), file: None });
+        None
+    };
+    let Some((name, body)) = def.split_once('=') else {
+        return error("Motif definition is missing '='".to_string(), span_of(line_content, def));
+    };
+    let (name, body) = (name.trim(), body.trim());
+    if !is_motif_name(name) {
+        return error(
+            format!("Invalid motif name '{}': use lowercase letters, digits and '_' (not a note name, 't' or 'r')", name),
+            span_of(line_content, name),
+        );
+    }
+    if let Some(existing) = motifs.get(name) {
+        return error(
+            format!("Motif '{}' is already defined at {}", name, existing.location),
+            span_of(line_content, name),
+        );
+    }
+    let span = span_of(line_content, body);
+    let inner = match body.strip_prefix('[').and_then(|b| b.strip_suffix(']')) {
+        Some(inner) if !inner.trim().is_empty() => inner,
+        _ => return error(format!("Motif '{}' must be written as '[beat, ...]'", name), span),
+    };
+    let beats = split_beats(tokenize(inner, span_of(line_content, inner).start, ctx.relative.is_some()));
+
+    // 小節の外で単独に解析できるか確かめる（IDは捨てる）
+    let mut id_gen = IdGenerator::default();
+    let mut prev = Vec::new();
+    let mut valid = true;
+    for beat_tokens in &beats {
+        match parse_beat(beat_tokens, &prev, ctx, &mut id_gen) {
+            Ok(beat) => prev.extend(beat.elements),
+            Err(errs) => {
+                valid = false;
+                errors.extend(errs.into_iter().map(|e| ParseError {
+                    message: format!("{} (in motif '{}')", e.message, name),
+                    line: Some(line_idx),
+                    span: e.span,
+                    file: None,
+                }));
+            }
+        }
+    }
+    let location = sources.describe(line_idx);
+    Some((name.to_string(), Motif { beats, line: line_idx, location, span, valid }))
+}
+
+/// 拍がモチーフの使用（`name` / `name^+2`）なら、その名前・定義・移調量・範囲を返す。
+/// 同じ名前のモチーフがなく打楽器の音（`kick`）なら、モチーフの使用ではない。
+/// 相対オクターブ入力（`relative`）では、音名として読める `c` や `eb` は常に音符になる
+fn expand_motif_use<'a>(
+    tokens: &'a [Token],
+    motifs: &'a HashMap<String, Motif>,
+    drums: &[DrumSound],
+    relative: bool,
+) -> Result<Option<(&'a str, &'a Motif, i32, Span)>, ParseError> {
+    let [token] = tokens else { return Ok(None) };
+    let (name, transpose) = match token.as_str().split_once('^') {
+        Some((name, t)) => (name, Some(t)),
+        None => (token.as_str(), None),
+    };
+    if !is_motif_name(name)
+        || (transpose.is_none() && !motifs.contains_key(name) && drums.iter().any(|d| d.name == name))
+        || (relative && transpose.is_none() && Pitch::is_relative_spelling(name))
+    {
+        return Ok(None);
+    }
+    let error = |message: String| ParseError { message, line: None, span: Some(token.span), file: None };
+    let Some(motif) = motifs.get(name) else {
+        let candidates = motifs.keys().map(String::as_str).chain(drums.iter().map(|d| d.name.as_str()));
+        return Err(error(format!("Unknown motif or drum '{}'{}", name, hint(did_you_mean(name, candidates)))));
+    };
+    if !motif.valid {
+        return Err(error(format!("Motif '{}' has errors (defined at {})", name, motif.location)));
+    }
+    let semitones = match transpose {
+        None => 0,
+        Some(t) => t.parse::<i32>().map_err(|_| {
+            error(format!("Invalid transposition '^{}' for motif '{}': expected semitones such as '^+2' or '^-12'", t, name))
+        })?,
+    };
+    Ok(Some((name, motif, semitones, token.span)))
+}
+
+/// 要素列の音高を半音単位で移調する
+fn transpose_elements(elements: &mut [ScoreElement], semitones: i32) -> Result<(), String> {
+    if semitones == 0 {
+        return Ok(());
+    }
+    fn transpose(pitch: &mut Option<Pitch>, pitch_cents: &mut Option<u16>, semitones: i32) -> Result<(), String> {
+        if let Some(p) = pitch {
+            *p = p.transposed(semitones)?;
+        }
+        if let Some(c) = pitch_cents {
+            let cents = *c as i32 + semitones * 100;
+            if !(0..=MAX_PITCH_CENTS).contains(&cents) {
+                return Err(format!("Transposition by {} semitones is out of range", semitones));
+            }
+            *c = cents as u16;
+        }
+        Ok(())
+    }
+    for elem in elements {
+        match elem {
+            ScoreElement::Event(ev) if ev.unpitched.is_some() => {}
+            ScoreElement::Event(ev) => {
+                transpose(&mut ev.pitch, &mut ev.pitch_cents, semitones)?;
+                for grace in &mut ev.grace_notes {
+                    grace.pitch = grace.pitch.transposed(semitones)?;
+                    transpose(&mut None, &mut grace.pitch_cents, semitones)?;
+                }
+            }
+            ScoreElement::Tie(tie) => transpose(&mut tie.pitch, &mut tie.pitch_cents, semitones)?,
+            ScoreElement::Chord(chord) => {
+                for ev in chord.events.iter_mut().filter(|ev| ev.unpitched.is_none()) {
+                    transpose(&mut ev.pitch, &mut ev.pitch_cents, semitones)?;
+                }
+            }
+            ScoreElement::Subdivision(sub) => transpose_elements(&mut sub.elements, semitones)?,
+        }
+    }
+    Ok(())
+}
+
 /// Remove comments from the whole input string.
 /// Supports both // (line) and /* ... */ (block, possibly multi-line) comments.
-/// Preserves newline characters to maintain line structure.
-fn remove_comments_multiline(input: &str) -> String {
-    let mut result = String::new();
+/// Comment text is replaced with spaces of the same byte length and newline
+/// characters are preserved, so line and column positions stay unchanged.
+pub(crate) fn remove_comments_multiline(input: &str) -> String {
+    fn blank(result: &mut String, c: char) {
+        if c == '\n' {
+            result.push('\n');
+        } else {
+            result.extend(std::iter::repeat_n(' ', c.len_utf8()));
+        }
+    }
+
+    let mut result = String::with_capacity(input.len());
     let mut chars = input.chars().peekable();
     let mut in_block = false;
 
@@ -218,15 +1166,18 @@ fn remove_comments_multiline(input: &str) -> String {
         if !in_block && c == '/' {
             if let Some(&next) = chars.peek() {
                 if next == '/' {
+                    blank(&mut result, c);
                     for nc in chars.by_ref() {
+                        blank(&mut result, nc);
                         if nc == '\n' {
-                            result.push('\n');
                             break;
                         }
                     }
                     continue;
                 } else if next == '*' {
                     in_block = true;
+                    blank(&mut result, c);
+                    blank(&mut result, next);
                     chars.next();
                     continue;
                 } else {
@@ -236,58 +1187,350 @@ fn remove_comments_multiline(input: &str) -> String {
                 result.push(c);
             }
         } else if in_block && c == '*' {
+            blank(&mut result, c);
             if let Some(&next) = chars.peek() {
                 if next == '/' {
                     in_block = false;
+                    blank(&mut result, next);
                     chars.next();
                     continue;
                 }
             }
         } else if !in_block {
             result.push(c);
-        } else if c == '\n' {
-            result.push('\n');
+        } else {
+            blank(&mut result, c);
         }
     }
     result
 }
 
-/// テキスト入力をトークナイズ→パースして Score に変換します。
-/// 複数エラーをVec<ParseError>で返す
-pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
-    let cleaned_input = remove_comments_multiline(input);
-    let mut parts = Vec::new();
-    let mut current_part_name: Option<String> = None;
-    let mut current_measures = Vec::new();
-    let mut current_meter: Option<(usize, usize)> = None;
-    let mut errors = Vec::new();
+/// 解析する入力の各行が、どのファイルの何行目から来たか（`#include` の展開用）
+#[derive(Default)]
+struct SourceMap {
+    lines: Vec<(PathBuf, usize)>,
+}
 
-    for (line_idx, line_content) in cleaned_input.lines().enumerate() {
-        let line = line_content.trim();
+impl SourceMap {
+    /// 入力の行番号を (ファイル, ファイル内の行番号) に変換する
+    fn locate(&self, line: usize) -> (Option<&Path>, usize) {
+        match self.lines.get(line) {
+            Some((file, l)) => (Some(file.as_path()), *l),
+            None => (None, line),
+        }
+    }
+
+    /// メッセージ中で行を示す文字列（"line 3" / "line 3 of strings/m1-10.vsc"）
+    fn describe(&self, line: usize) -> String {
+        match self.locate(line) {
+            (Some(file), l) => format!("line {} of {}", l + 1, file.display()),
+            (None, l) => format!("line {}", l + 1),
+        }
+    }
+}
+
+/// `#include "path"` の引用符内のパスを取り出す
+fn parse_include_path(directive: &str) -> Option<&str> {
+    let path = directive.trim().strip_prefix('"')?.strip_suffix('"')?;
+    (!path.is_empty()).then_some(path)
+}
+
+/// `path` の内容 `input` を `out` に追加し、`#include` を読み込むファイルからの相対パスで再帰的に展開する。
+/// `stack` は展開中のファイル（正規化済み）で、循環した include を検出するのに使う。
+fn expand_includes(
+    path: &Path,
+    input: &str,
+    stack: &mut Vec<PathBuf>,
+    out: &mut String,
+    sources: &mut SourceMap,
+    errors: &mut Vec<ParseError>,
+) {
+    stack.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    // コメントはファイルごとに除去する（ブロックコメントがファイルをまたがないように）
+    let cleaned = remove_comments_multiline(input);
+    for (line_idx, line_content) in cleaned.lines().enumerate() {
+        let Some(directive) = line_content.trim().strip_prefix("#include") else {
+            out.push_str(line_content);
+            out.push('\n');
+            sources.lines.push((path.to_path_buf(), line_idx));
+            continue;
+        };
+        let mut error = |message: String| errors.push(ParseError {
+            message,
+            line: Some(line_idx),
+            span: Some(span_of(line_content, line_content.trim())),
+            file: Some(path.to_path_buf()),
+        });
+        let Some(target) = parse_include_path(directive) else {
+            error("Expected a quoted path after #include (e.g. #include \"strings/m1-10.vsc\")".to_string());
+            continue;
+        };
+        let child = path.parent().unwrap_or(Path::new("")).join(target);
+        let content = child.canonicalize().and_then(|c| Ok((std::fs::read_to_string(&c)?, c)));
+        match content {
+            Ok((_, canonical)) if stack.contains(&canonical) => {
+                let cycle: Vec<String> = stack.iter()
+                    .skip_while(|p| **p != canonical)
+                    .chain(std::iter::once(&canonical))
+                    .map(|p| p.display().to_string())
+                    .collect();
+                error(format!("Include cycle: {}", cycle.join(" -> ")));
+            }
+            Ok((content, _)) => expand_includes(&child, &content, stack, out, sources, errors),
+            Err(e) => error(format!("Cannot read included file '{}': {}", child.display(), e)),
+        }
+    }
+    stack.pop();
+}
+
+/// テキスト入力をトークナイズ→パースして Score に変換します。
+/// 複数エラーをVec<ParseError>で返す
+#[allow(dead_code)] // 文字列から解析する公開API（CLIはparse_score_fileを使う）
+pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
+    parse_source(input, &SourceMap::default())
+}
+
+/// VSCファイルを読み込んで Score に変換します。
+/// `#include "path"` は読み込むファイルからの相対パスで展開し、エラーには元のファイルを記録します。
+pub fn parse_score_file(path: impl AsRef<Path>) -> Result<Score, Vec<ParseError>> {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(input) => parse_score_with_path(&input, path),
+        Err(e) => Err(vec![ParseError {
+            message: format!("Cannot read file: {}", e),
+            line: None,
+            span: None,
+            file: Some(path.to_path_buf()),
+        }]),
+    }
+}
+
+/// `path` のファイルの内容として `input` を解析します（エディタ上の未保存の内容など）。
+/// `#include` は `path` からの相対パスで展開します。
+pub fn parse_score_with_path(input: &str, path: &Path) -> Result<Score, Vec<ParseError>> {
+    let mut expanded = String::new();
+    let mut sources = SourceMap::default();
+    let mut errors = Vec::new();
+    expand_includes(path, input, &mut Vec::new(), &mut expanded, &mut sources, &mut errors);
+    let result = parse_source(&expanded, &sources).map_err(|errs| {
+        errs.into_iter().map(|mut e| {
+            if let Some(line) = e.line {
+                let (file, l) = sources.locate(line);
+                e.file = file.map(Path::to_path_buf);
+                e.line = Some(l);
+            }
+            e
+        }).collect::<Vec<_>>()
+    });
+    match result {
+        Ok(score) if errors.is_empty() => Ok(score),
+        Ok(_) => Err(errors),
+        Err(errs) => {
+            errors.extend(errs);
+            Err(errors)
+        }
+    }
+}
+
+/// include 展開後の入力を解析する。`sources` はメッセージ中の行の表示に使う。
+fn parse_source(input: &str, sources: &SourceMap) -> Result<Score, Vec<ParseError>> {
+    let cleaned_input = remove_comments_multiline(input);
+    let mut parts = Vec::new();
+    // 解析中のパートのヘッダー（小節は build_part で入れる）とヘッダーの行
+    let mut current_part: Option<(Part, usize)> = None;
+    let mut current_measures = Vec::new();
+    // ((分子, 分母), 拍ごとの分母単位数)
+    let mut current_meter: Option<((usize, usize), Vec<usize>)> = None;
+    let mut current_voice: usize = 1;
+    let mut current_staff: usize = 1;
+    // 小節番号ごとのID生成器（複数の声部で同じ小節のIDが重複しないように共有する）
+    let mut id_gens: HashMap<usize, IdGenerator> = HashMap::new();
+    // `let` で定義されたモチーフ（定義行以降のすべてのパートで使える）
+    let mut motifs: HashMap<String, Motif> = HashMap::new();
+    // (譜表, 声部) ごとの直前の小節番号（順序の検査と `+:` の自動採番に使う）
+    let mut last_measure_nos: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+    // パート内の小節番号ごとの最初の定義行と番号の範囲（欠番の検査に使う）
+    let mut measure_lines: BTreeMap<usize, (usize, Span)> = BTreeMap::new();
+    // 相対オクターブ入力（`#[Relative]`）の最初の基準音と、(譜表, 声部) ごとの直前の音
+    let mut relative_start: Option<Pitch> = None;
+    let mut relative_refs: HashMap<(usize, usize), Pitch> = HashMap::new();
+    // 名前で書ける打楽器の音（組み込みのGeneral MIDIに `#[Drum(...)]` で追加・上書きする。以降のすべてのパートで使える）
+    let mut drums = DrumSound::general_midi();
+    // 解析中の `#[Lyrics]` ブロック（次のヘッダーまで）
+    let mut lyrics_block: Option<LyricsBlock> = None;
+    let mut errors = Vec::new();
+
+    for (line_idx, line_content) in cleaned_input.lines().enumerate() {
+        let line = line_content.trim();
         if line.is_empty() {
             continue;
         }
+        // 歌詞の行 `N: Hel- lo world_`
+        if line.starts_with("#[") {
+            lyrics_block = None;
+        } else if let (Some(block), Some((part, _))) = (lyrics_block.as_mut(), current_part.as_mut()) {
+            if !line.starts_with("let ") {
+                if let Err(e) = parse_lyrics_line(line, line_content, block, &current_measures, &mut part.lyrics) {
+                    errors.extend(e.into_iter().map(|e| ParseError { line: Some(line_idx), ..e }));
+                }
+                continue;
+            }
+        }
         // Part header detection
         if line.starts_with("#[Part(") && line.ends_with(")]") {
             // If there is a previous part, push it
-            if let Some(name) = current_part_name.take() {
+            if let Some((header, header_line)) = current_part.take() {
+                errors.extend(check_measure_numbers(&header.name, &measure_lines));
+                errors.extend(check_trailing_dash_ties(&current_measures, &last_measure_nos));
                 if !current_measures.is_empty() {
-                    parts.push(crate::data::Part {
-                        name,
-                        measures: current_measures.clone(),
-                    });
-                    current_measures.clear();
+                    match build_part(header, std::mem::take(&mut current_measures), &id_gens) {
+                        Ok(part) => parts.push(part),
+                        Err(message) => errors.push(ParseError { message, line: Some(header_line), span: None, file: None }),
+                    }
+                }
+            }
+            // Extract part name and attributes
+            let spec = line.trim_start_matches("#[Part(").trim_end_matches(")]");
+            match parse_part_header(spec, line_content) {
+                Ok(header) => current_part = Some((header, line_idx)),
+                Err(e) => {
+                    errors.extend(e.into_iter().map(|e| ParseError { line: Some(line_idx), ..e }));
+                    // 属性が読めなくてもパートとして解析を続ける
+                    let name = spec.split(',').next().unwrap_or("").trim().to_string();
+                    current_part = Some((new_part(name), line_idx));
                 }
             }
-            // Extract part name
-            let name = line.trim_start_matches("#[Part(").trim_end_matches(")]").to_string();
-            current_part_name = Some(name);
             current_meter = None;
+            current_voice = 1;
+            current_staff = 1;
+            id_gens.clear();
+            last_measure_nos.clear();
+            measure_lines.clear();
+            relative_start = None;
+            relative_refs.clear();
+            continue;
+        }
+        // Lyrics block: `#[Lyrics]`（現在の譜表・声部の1番）、`#[Lyrics(voice=2, verse=2)]`
+        if line == "#[Lyrics]" || (line.starts_with("#[Lyrics(") && line.ends_with(")]")) {
+            let spec = line.trim_start_matches("#[Lyrics").trim_end_matches(']').trim_matches(['(', ')']).trim();
+            if current_part.is_none() {
+                errors.push(ParseError {
+                    message: "#[Lyrics] must be written inside a part (after #[Part(...)])".to_string(),
+                    line: Some(line_idx),
+                    span: Some(span_of(line_content, line)),
+                    file: None,
+                });
+                continue;
+            }
+            match parse_lyrics_header(spec, line_content, current_staff, current_voice) {
+                Ok(block) => lyrics_block = Some(block),
+                Err(e) => errors.extend(e.into_iter().map(|e| ParseError { line: Some(line_idx), ..e })),
+            }
+            continue;
+        }
+        // Drum map entry: `#[Drum(cowbell2, key=56, display=E5, notehead=triangle)]`
+        if line.starts_with("#[Drum(") && line.ends_with(")]") {
+            let spec = line.trim_start_matches("#[Drum(").trim_end_matches(")]");
+            match parse_drum_definition(spec, line_content) {
+                Ok(drum) => match drums.iter_mut().find(|d| d.name == drum.name) {
+                    Some(existing) => *existing = drum,
+                    None => drums.push(drum),
+                },
+                Err(e) => errors.extend(e.into_iter().map(|e| ParseError { line: Some(line_idx), ..e })),
+            }
+            continue;
+        }
+        // Octave entry mode: `#[Relative]`（基準音 C4）、`#[Relative(C5)]`、`#[Absolute]`
+        if line == "#[Absolute]" {
+            relative_start = None;
+            continue;
+        }
+        if line == "#[Relative]" || (line.starts_with("#[Relative(") && line.ends_with(")]")) {
+            let spec = line.trim_start_matches("#[Relative").trim_end_matches(']').trim_matches(['(', ')']).trim();
+            let start = if spec.is_empty() {
+                Ok(Pitch::NoteName { letter: NoteLetter::C, accidental: None, octave: 4 })
+            } else {
+                spec.parse::<Pitch>()
+            };
+            match start {
+                Ok(pitch) => {
+                    relative_start = Some(pitch);
+                    relative_refs.clear();
+                }
+                Err(e) => {
+                    errors.push(ParseError {
+                        message: format!("Invalid relative start pitch '{}': {}", spec, e),
+                        line: Some(line_idx),
+                        span: Some(span_of(line_content, spec)),
+                        file: None,
+                    });
+                }
+            }
+            continue;
+        }
+        // Staff header detection: 以降の小節行を指定した譜表に割り当てる（声部は1に戻る）
+        if line.starts_with("#[Staff(") && line.ends_with(")]") {
+            let num_str = line.trim_start_matches("#[Staff(").trim_end_matches(")]").trim();
+            match num_str.parse::<usize>() {
+                Ok(n) if (1..=MAX_STAFF_COUNT).contains(&n) => {
+                    current_staff = n;
+                    current_voice = 1;
+                    current_meter = None;
+                }
+                _ => {
+                    errors.push(ParseError {
+                        message: format!("Invalid staff number '{}': expected an integer from 1 to {}", num_str, MAX_STAFF_COUNT),
+                        line: Some(line_idx),
+                        span: Some(span_of(line_content, num_str)),
+                        file: None,
+                    });
+                }
+            }
+            continue;
+        }
+        // Voice header detection: 以降の小節行を指定した声部として扱う
+        if line.starts_with("#[Voice(") && line.ends_with(")]") {
+            let num_str = line.trim_start_matches("#[Voice(").trim_end_matches(")]").trim();
+            match num_str.parse::<usize>() {
+                Ok(n) if n > 0 => {
+                    current_voice = n;
+                    current_meter = None;
+                }
+                _ => {
+                    errors.push(ParseError {
+                        message: format!("Invalid voice number '{}': expected an integer of 1 or more", num_str),
+                        line: Some(line_idx),
+                        span: Some(span_of(line_content, num_str)),
+                        file: None,
+                    });
+                }
+            }
+            continue;
+        }
+
+        // Include directive: ファイルを解析するときに展開済み
+        if line.starts_with("#include") {
+            errors.push(ParseError {
+                message: "#include can only be used when parsing a file (parse_score_file)".to_string(),
+                line: Some(line_idx),
+                span: Some(span_of(line_content, line)),
+                file: None,
+            });
+            continue;
+        }
+        // Motif definition: `let name = [...]`
+        if let Some(def) = line.strip_prefix("let ") {
+            let ctx = TieContext { measure: 0, prev_measure: None, relative: relative_start.as_ref(), drums: &drums };
+            if let Some((name, motif)) = parse_motif_definition(def, line_content, line_idx, sources, &motifs, &ctx, &mut errors) {
+                motifs.insert(name, motif);
+            }
             continue;
         }
 
         let mut line_errors = Vec::new();
         let mut measure_no = 0;
+        // 複数小節の休符（`12-20: R`）の最後の小節番号
+        let mut range_end: Option<usize> = None;
         let mut line_after_measure_no = "";
         // measure_no, line_after_measure_no の取得
         match line.find(':') {
@@ -297,7 +1540,32 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
                     line_errors.push(ParseError {
                         message: "Measure number is missing before ':'".to_string(),
                         line: Some(line_idx),
+                        span: Some(span_of(line_content, &line[idx..idx + 1])),
+                        file: None,
                     });
+                } else if num_str == "+" {
+                    // 自動採番: 同じ譜表・声部の直前の小節の次（最初ならパートの最初の小節）
+                    measure_no = match last_measure_nos.get(&(current_staff, current_voice)) {
+                        Some((last, _)) => last + 1,
+                        None => measure_lines.keys().next().copied().unwrap_or(1),
+                    };
+                    line_after_measure_no = line[idx + 1..].trim();
+                } else if let Some((first, last)) = num_str.split_once('-') {
+                    match (first.trim().parse::<usize>(), last.trim().parse::<usize>()) {
+                        (Ok(first), Ok(last)) if first < last => {
+                            measure_no = first;
+                            range_end = Some(last);
+                            line_after_measure_no = line[idx + 1..].trim();
+                        }
+                        _ => {
+                            line_errors.push(ParseError {
+                                message: format!("Invalid measure range '{}': expected increasing numbers such as 12-20", num_str),
+                                line: Some(line_idx),
+                                span: Some(span_of(line_content, num_str)),
+                                file: None,
+                            });
+                        }
+                    }
                 } else {
                     match num_str.parse::<usize>() {
                         Ok(num) => {
@@ -308,6 +1576,8 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
                             line_errors.push(ParseError {
                                 message: format!("Invalid measure number '{}'", num_str),
                                 line: Some(line_idx),
+                                span: Some(span_of(line_content, num_str)),
+                                file: None,
                             });
                         }
                     }
@@ -317,6 +1587,8 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
                 line_errors.push(ParseError {
                     message: "Measure number separator ':' is missing".to_string(),
                     line: Some(line_idx),
+                    span: Some(span_of(line_content, line)),
+                    file: None,
                 });
             }
         }
@@ -325,9 +1597,31 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
             continue;
         }
 
+        // 小節番号は声部ごとに増えていく必要がある（同じ番号は後で重複として報告する）
+        let number_span = span_of(line_content, line[..line.find(':').unwrap_or(0)].trim());
+        if let Some(&(last, _)) = last_measure_nos.get(&(current_staff, current_voice)) {
+            if measure_no < last {
+                errors.push(ParseError {
+                    message: format!(
+                        "Measure {} is out of order: staff {} voice {} already reached measure {}",
+                        measure_no, current_staff, current_voice, last
+                    ),
+                    line: Some(line_idx),
+                    span: Some(number_span),
+                    file: None,
+                });
+                continue;
+            }
+        }
+        // 順番が正しい小節だけを番号の抜けの確認に使う
+        for number in measure_no..=range_end.unwrap_or(measure_no) {
+            measure_lines.entry(number).or_insert((line_idx, number_span));
+        }
+        last_measure_nos.insert((current_staff, current_voice), (range_end.unwrap_or(measure_no), line_idx));
+
         let (meter, content) = if let Some((meter_part, rest)) = line_after_measure_no.split_once(' ') {
-            if let Some((num, denom)) = parse_meter(meter_part) {
-                (Some((num, denom)), rest.trim())
+            if let Some(meter) = parse_meter(meter_part) {
+                (Some(meter), rest.trim())
             } else {
                 (None, line_after_measure_no)
             }
@@ -335,15 +1629,72 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
             (None, line_after_measure_no)
         };
 
+        // 他の声部で既に定義済みの小節なら、その拍子を引き継ぐ
+        let existing_meter = current_measures.iter()
+            .find(|m: &&Measure| m.number == measure_no)
+            .map(|m| (m.meter, m.beat_groups.clone()));
+        if let (Some(m), Some(e)) = (&meter, &existing_meter) {
+            if m != e {
+                errors.push(ParseError {
+                    message: format!(
+                        "Meter {} of staff {} voice {} does not match meter {} of another voice (Measure {})",
+                        format_meter(m.0, &m.1), current_staff, current_voice, format_meter(e.0, &e.1), measure_no
+                    ),
+                    line: Some(line_idx),
+                    span: line_after_measure_no.split_whitespace().next().map(|m| span_of(line_content, m)),
+                    file: None,
+                });
+                continue;
+            }
+        }
+        if let Some(e) = existing_meter {
+            current_meter = Some(e);
+        }
+
+        // 弱起・不完全小節の指定（他の声部で定義済みならそれを引き継ぐ）
+        let (length_tag, content) = split_length_tag(content);
+        let existing_length = current_measures.iter()
+            .find(|m: &&Measure| m.number == measure_no)
+            .map(|m| m.length);
+        let length = match length_tag.map(parse_length_tag) {
+            Some(Err(message)) => {
+                errors.push(ParseError {
+                    message: format!("{} (Measure {})", message, measure_no),
+                    line: Some(line_idx),
+                    span: length_tag.map(|t| span_of(line_content, t)),
+                    file: None,
+                });
+                continue;
+            }
+            Some(Ok(l)) => match existing_length {
+                Some(e) if e != l => {
+                    errors.push(ParseError {
+                        message: format!(
+                            "Measure length '{}' of staff {} voice {} does not match another voice (Measure {})",
+                            length_tag.unwrap_or(""), current_staff, current_voice, measure_no
+                        ),
+                        line: Some(line_idx),
+                        span: length_tag.map(|t| span_of(line_content, t)),
+                        file: None,
+                    });
+                    continue;
+                }
+                _ => l,
+            },
+            None => existing_length.unwrap_or(MeasureLength::Full),
+        };
+
         if current_meter.is_none() && meter.is_none() && current_measures.is_empty() {
             errors.push(ParseError {
                 message: format!("No meter specified in the first measure (Measure {})", measure_no),
                 line: Some(line_idx),
+                span: Some(span_of(line_content, line_after_measure_no)),
+                file: None,
             });
             continue;
         } else if current_meter.is_none() && meter.is_none() && !current_measures.is_empty() {
             // 何もしない
-        } else if let Some(m) = meter {
+        } else if let Some(m) = meter.clone() {
             current_meter = Some(m);
         }
 
@@ -352,112 +1703,316 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
             errors.push(ParseError {
                 message: format!("No content found after measure number/meter (Measure {})", measure_no),
                 line: Some(line_idx),
+                span: Some(span_of(line_content, line)),
+                file: None,
             });
             continue;
         }
 
-        let current_meter_val = match current_meter {
+        let (current_meter_val, beat_groups) = match current_meter.clone() {
             Some(m) => m,
             None => {
+                // 声部の最初の小節が、他の声部にない小節で拍子も書かれていない場合
                 errors.push(ParseError {
-                    message: format!("Internal error: Meter not set (Measure {})", measure_no),
+                    message: format!(
+                        "No meter for staff {} voice {} (Measure {}): write a meter such as 4/4 on this line, or define the measure in another voice first",
+                        current_staff, current_voice, measure_no
+                    ),
                     line: Some(line_idx),
+                    span: Some(span_of(line_content, line_after_measure_no)),
+                    file: None,
                 });
                 continue;
             }
         };
-
-        let start = match content.find('[') {
-            Some(s) => s,
-            None => {
+        if let MeasureLength::Partial(n) = length {
+            if n > current_meter_val.0 {
                 errors.push(ParseError {
-                    message: format!("Missing '[' in content '{}' (Measure {})", content, measure_no),
+                    message: format!(
+                        "Partial measure length ({}) exceeds meter {} ({} beats) (Measure {})",
+                        n, format_meter(current_meter_val, &beat_groups), current_meter_val.0, measure_no
+                    ),
                     line: Some(line_idx),
+                    span: length_tag.map(|t| span_of(line_content, t)),
+                    file: None,
                 });
                 continue;
             }
+        }
+
+        // 全休符の小節は括弧を省略して `R` とだけ書ける（`12-20: R`）
+        let bare_rest = match content.find('[') {
+            None => content.split_whitespace().find(|w| *w == "R"),
+            Some(_) => None,
         };
-        let end = match content.rfind(']') {
-            Some(e) => e,
-            None => {
-                errors.push(ParseError {
-                    message: format!("Missing ']' (Measure {})", measure_no),
+        let (before, body, after) = if let Some(rest) = bare_rest {
+            let start = span_of(content, rest).start;
+            (&content[..start], rest, &content[start + 1..])
+        } else {
+            let start = match content.find('[') {
+                Some(s) => s,
+                None => {
+                    errors.push(ParseError {
+                        message: format!("Missing '[' in content '{}' (Measure {})", content, measure_no),
+                        line: Some(line_idx),
+                        span: Some(span_of(line_content, content)),
+                        file: None,
+                    });
+                    continue;
+                }
+            };
+            let end = match content.rfind(']') {
+                Some(e) if e > start => e,
+                _ => {
+                    errors.push(ParseError {
+                        message: format!("Missing ']' (Measure {})", measure_no),
+                        line: Some(line_idx),
+                        span: Some(span_of(line_content, &content[start..])),
+                        file: None,
+                    });
+                    continue;
+                }
+            };
+            (&content[..start], &content[start..=end], &content[end + 1..])
+        };
+        // `[...]` の外に書かれた反復・ナビゲーション記号
+        let mut marks = MeasureMarks::default();
+        let mut mark_errors = Vec::new();
+        for word in before.split_whitespace().chain(after.split_whitespace()) {
+            if let Err(message) = parse_measure_mark(word, current_meter_val.0, &mut marks) {
+                mark_errors.push(ParseError {
+                    message: format!("{} (Measure {})", message, measure_no),
                     line: Some(line_idx),
+                    span: Some(span_of(line_content, word)),
+                    file: None,
                 });
-                continue;
             }
+        }
+        // 記号に誤りがあっても拍の誤りは続けて調べる（小節は追加しない）
+        let marks_valid = mark_errors.is_empty();
+        errors.extend(mark_errors);
+        let inner = if bare_rest.is_some() { body } else { &body[1..body.len() - 1] };
+        let content_span = span_of(line_content, body);
+        // 小節全体の休符 `R`（拍子によらず1つの休符で小節を埋める）
+        let measure_rest = inner.trim() == "R";
+        if let (Some(last), false) = (range_end, measure_rest) {
+            errors.push(ParseError {
+                message: format!("A measure range ({}-{}) can only hold a whole-measure rest `R`", measure_no, last),
+                line: Some(line_idx),
+                span: Some(content_span),
+                file: None,
+            });
+            continue;
+        }
+        if measure_rest && length != MeasureLength::Full {
+            errors.push(ParseError {
+                message: format!("Whole-measure rest `R` cannot be used in a pickup or partial measure (Measure {})", measure_no),
+                line: Some(line_idx),
+                span: Some(content_span),
+                file: None,
+            });
+            continue;
+        }
+        let tokens = if measure_rest {
+            Vec::new()
+        } else {
+            tokenize(inner, span_of(line_content, inner).start, relative_start.is_some())
         };
-        let inner = &content[start + 1..end];
-        let tokens = tokenize(inner);
-
+        // 括弧の対応が崩れている場合は拍の区切りが当てにならないので拍数は検査しない
+        let brackets_balanced = brackets_balanced(&tokens);
+        // 誤りのため読めなかった拍の数（拍数の検査に含める）
+        let mut failed_beats = 0;
         let mut beats = Vec::new();
-        let mut beat_tokens = Vec::new();
-        let mut depth = 0;
         let mut beat_errors = Vec::new();
         // --- ID生成用 ---
-        let mut id_gen = IdGenerator::default();
-
-        for token in tokens {
-            match token.as_str() {
-                "[" | "{" => { depth += 1; beat_tokens.push(token); }
-                "]" | "}" => { depth -= 1; beat_tokens.push(token); }
-                "," if depth == 0 => {
-                    if !beat_tokens.is_empty() {
-                        match parse_tokens(&beat_tokens, &[], &mut id_gen) {
-                            Ok(elements) => beats.push(Beat { elements, duration: 0.0 }),
-                            Err(mut e) => {
-                                e.line = e.line.or(Some(line_idx));
-                                beat_errors.push(e);
-                            }
+        let id_gen = id_gens.entry(measure_no).or_default();
+        // タイの接続元を探すための、同じ譜表・声部の直前の小節
+        let prev_measure: Option<(usize, Vec<ScoreElement>)> = current_measures.iter().rev()
+            .filter(|m| m.number != measure_no)
+            .find_map(|m| {
+                m.voices.iter()
+                    .find(|v| v.staff == current_staff && v.number == current_voice)
+                    .map(|v| (m.number, v.beats.iter().flat_map(|b| b.elements.iter().cloned()).collect()))
+            });
+        // 相対オクターブ入力の基準音（同じ譜表・声部の直前の音、なければ `#[Relative(...)]` の音）
+        let relative_ref = relative_start.as_ref()
+            .map(|start| relative_refs.get(&(current_staff, current_voice)).unwrap_or(start).clone());
+        let tie_ctx = TieContext {
+            measure: measure_no,
+            prev_measure: prev_measure.as_ref().map(|(n, elements)| (*n, elements.as_slice())),
+            relative: relative_ref.as_ref(),
+            drums: &drums,
+        };
+        // この小節内でこれまでに読んだ拍の要素
+        let mut line_prev: Vec<ScoreElement> = Vec::new();
+
+        for beat_tokens in split_beats(tokens) {
+            // モチーフの使用なら定義の拍に置き換える
+            let motif_use = match expand_motif_use(&beat_tokens, &motifs, &drums, relative_ref.is_some()) {
+                Ok(u) => u,
+                Err(e) => {
+                    beat_errors.push(ParseError { line: Some(line_idx), ..e });
+                    failed_beats += 1;
+                    continue;
+                }
+            };
+            let Some((name, motif, semitones, use_span)) = motif_use else {
+                match parse_beat(&beat_tokens, &line_prev, &tie_ctx, id_gen) {
+                    Ok(beat) => {
+                        if let Err(message) = check_dash_ties(&beat, &line_prev, &tie_ctx) {
+                            let span = beat_tokens.first().map(|t| t.span.to(beat_tokens[beat_tokens.len() - 1].span));
+                            beat_errors.push(ParseError { message, line: Some(line_idx), span, file: None });
                         }
-                        beat_tokens.clear();
+                        line_prev.extend(beat.elements.iter().cloned());
+                        beats.push(beat);
+                    }
+                    Err(errs) => {
+                        beat_errors.extend(errs.into_iter().map(|e| ParseError { line: e.line.or(Some(line_idx)), ..e }));
+                        failed_beats += 1;
                     }
                 }
-                _ => { beat_tokens.push(token); }
-            }
-        }
-        if !beat_tokens.is_empty() {
-            match parse_tokens(&beat_tokens, &[], &mut id_gen) {
-                Ok(elements) => beats.push(Beat { elements, duration: 0.0 }),
-                Err(mut e) => {
-                    e.line = e.line.or(Some(line_idx));
-                    beat_errors.push(e);
+                continue;
+            };
+            // 移調するモチーフは、直前の要素を逆に移調した文脈で解析してから移調する。
+            // タイ・スライド・`-` の接続元の音高が二重に移調されないようにするため
+            let untransposed = |elements: &[ScoreElement]| {
+                let mut shadow = elements.to_vec();
+                match transpose_elements(&mut shadow, -semitones) {
+                    Ok(()) => shadow,
+                    Err(_) => elements.to_vec(),
+                }
+            };
+            let shadow_prev_measure = tie_ctx.prev_measure.map(|(number, elements)| (number, untransposed(elements)));
+            let shadow_relative = tie_ctx.relative.map(|p| p.transposed(-semitones).unwrap_or_else(|_| p.clone()));
+            let motif_ctx = TieContext {
+                prev_measure: shadow_prev_measure.as_ref().map(|(number, elements)| (*number, elements.as_slice())),
+                relative: shadow_relative.as_ref(),
+                ..tie_ctx
+            };
+            for (k, motif_beat) in motif.beats.iter().enumerate() {
+                // 展開したトークンのエラーは使用箇所を指す
+                let tokens: Vec<Token> = motif_beat.iter()
+                    .map(|t| Token { text: t.text.clone(), span: use_span })
+                    .collect();
+                // 展開できない場合は最初の誤りだけを使用箇所と定義の両方に報告する
+                let result = parse_beat(&tokens, &untransposed(&line_prev), &motif_ctx, id_gen)
+                    .map_err(|mut errs| errs.swap_remove(0))
+                    .and_then(|mut beat| {
+                        transpose_elements(&mut beat.elements, semitones)
+                            .map_err(|message| ParseError { message, line: None, span: Some(use_span), file: None })?;
+                        Ok(beat)
+                    });
+                match result {
+                    Ok(beat) => {
+                        if let Err(message) = check_dash_ties(&beat, &line_prev, &tie_ctx) {
+                            beat_errors.push(ParseError { message, line: Some(line_idx), span: Some(use_span), file: None });
+                        }
+                        line_prev.extend(beat.elements.iter().cloned());
+                        beats.push(beat);
+                    }
+                    Err(e) => {
+                        beat_errors.push(ParseError {
+                            message: format!("{} (in motif '{}' defined at {})", e.message, name, motif.location),
+                            line: Some(line_idx),
+                            span: Some(use_span),
+                            file: None,
+                        });
+                        beat_errors.push(ParseError {
+                            message: format!("Motif '{}' cannot be expanded at {}: {}", name, sources.describe(line_idx), e.message),
+                            line: Some(motif.line),
+                            span: Some(motif.span),
+                            file: None,
+                        });
+                        failed_beats += motif.beats.len() - k;
+                        break;
+                    }
                 }
             }
         }
         if !beat_errors.is_empty() {
             errors.extend(beat_errors);
         }
+        if measure_rest {
+            beats.push(measure_rest_beat(id_gen, &beat_groups));
+        }
 
         // beats数のエラーは他のエラーと独立して追加
-        if beats.len() != current_meter_val.0 {
+        let other_voice_beats = current_measures.iter()
+            .find(|m| m.number == measure_no)
+            .and_then(|m| m.voices.first())
+            .map(|v| v.beats.len());
+        let beat_count_error = match beat_units(beats.len() + failed_beats, current_meter_val, &beat_groups, length) {
+            _ if measure_rest => None,
+            Err(_) if !brackets_balanced => None,
+            Err(message) => Some(message),
+            Ok(units) => {
+                for (beat, units) in beats.iter_mut().zip(units) {
+                    beat.units = units;
+                }
+                other_voice_beats
+                    .filter(|&n| length == MeasureLength::Pickup && n != beats.len())
+                    .map(|n| format!(
+                        "Number of beats ({}) does not match other voices ({}) in pickup measure",
+                        beats.len(), n
+                    ))
+            }
+        };
+        if let Some(message) = beat_count_error {
             errors.push(ParseError {
-                message: format!(
-                    "Number of beats ({}) does not match meter ({}) (Measure {})",
-                    beats.len(), current_meter_val.0, measure_no
-                ),
+                message: format!("{} (Measure {})", message, measure_no),
                 line: Some(line_idx),
+                span: Some(content_span),
+                file: None,
             });
         }
 
+        // 相対オクターブ入力の次の小節の基準音
+        if relative_start.is_some() {
+            let mut leaves = Vec::new();
+            collect_leaves(&line_prev, &mut leaves);
+            if let Some(pitch) = leaves.into_iter().rev().find_map(leaf_pitch) {
+                relative_refs.insert((current_staff, current_voice), pitch.clone());
+            }
+        }
+        if !marks_valid {
+            continue;
+        }
         // beatsが空でもmeasures.pushはしない（ただし他のエラーは収集）
-        if !beats.is_empty() {
-            current_measures.push(Measure {
-                number: measure_no,
-                beats,
-                duration: 0.0,
-                unit_duration: 0.0,
-                meter: current_meter_val,
-            });
+        if beats.is_empty() {
+            continue;
+        }
+        let last = range_end.unwrap_or(measure_no);
+        let rest = measure_rest.then_some(MeasureRest { first: measure_no, count: last - measure_no + 1 });
+        // 複数小節の休符では、始まりの記号は最初の小節に、終わりの記号は最後の小節に付ける
+        let (start_marks, end_marks) = split_marks(marks.clone());
+        for number in measure_no..=last {
+            let voice = if number == measure_no {
+                Voice { number: current_voice, staff: current_staff, beats: std::mem::take(&mut beats), measure_rest: rest }
+            } else {
+                let beats = vec![measure_rest_beat(id_gens.entry(number).or_default(), &beat_groups)];
+                Voice { number: current_voice, staff: current_staff, beats, measure_rest: rest }
+            };
+            let marks = match (number == measure_no, number == last) {
+                (true, true) => marks.clone(),
+                (true, false) => start_marks.clone(),
+                (false, true) => end_marks.clone(),
+                (false, false) => MeasureMarks::default(),
+            };
+            let meter = (current_meter_val, beat_groups.clone());
+            if let Err(message) = add_voice(&mut current_measures, number, voice, marks, length, meter) {
+                errors.push(ParseError { message, line: Some(line_idx), span: Some(content_span), file: None });
+            }
         }
     }
     // 最後のpartを追加
-    if let Some(name) = current_part_name {
+    if let Some((header, header_line)) = current_part {
+        errors.extend(check_measure_numbers(&header.name, &measure_lines));
+        errors.extend(check_trailing_dash_ties(&current_measures, &last_measure_nos));
         if !current_measures.is_empty() {
-            parts.push(crate::data::Part {
-                name,
-                measures: current_measures,
-            });
+            match build_part(header, current_measures, &id_gens) {
+                Ok(part) => parts.push(part),
+                Err(message) => errors.push(ParseError { message, line: Some(header_line), span: None, file: None }),
+            }
         }
     }
     if errors.is_empty() {
@@ -467,10 +2022,620 @@ pub fn parse_score(input: &str) -> Result<Score, Vec<ParseError>> {
     }
 }
 
-/// "4/4" のような文字列を (4,4) に変換
-fn parse_meter(s: &str) -> Option<(usize, usize)> {
-    let mut parts = s.split('/');
-    let num = parts.next()?.parse().ok()?;
-    let denom = parts.next()?.parse().ok()?;
-    Some((num, denom))
+/// パート内の小節番号が1（弱起があれば0）から欠番なく続いているか確かめる
+fn check_measure_numbers(part_name: &str, measure_lines: &BTreeMap<usize, (usize, Span)>) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut expected = match measure_lines.keys().next() {
+        Some(0) => 0,
+        _ => 1,
+    };
+    for (i, (&number, &(line, span))) in measure_lines.iter().enumerate() {
+        if number != expected {
+            let message = if i == 0 {
+                format!("Part '{}' must start at measure 1 (or 0 for a pickup), not {}", part_name, number)
+            } else {
+                format!("Measure numbers in part '{}' skip from {} to {}", part_name, expected - 1, number)
+            };
+            errors.push(ParseError { message, line: Some(line), span: Some(span), file: None });
+        }
+        expected = number + 1;
+    }
+    errors
+}
+
+/// 声部を小節に加える（小節がなければ作る）
+fn add_voice(
+    measures: &mut Vec<Measure>,
+    number: usize,
+    voice: Voice,
+    marks: MeasureMarks,
+    length: MeasureLength,
+    (meter, beat_groups): ((usize, usize), Vec<usize>),
+) -> Result<(), String> {
+    match measures.iter_mut().find(|m| m.number == number) {
+        Some(measure) if measure.voices.iter().any(|v| v.staff == voice.staff && v.number == voice.number) => Err(format!(
+            "Voice {} of staff {} in measure {} is defined more than once",
+            voice.number, voice.staff, number
+        )),
+        Some(measure) if marks != MeasureMarks::default() && measure.marks != MeasureMarks::default() && marks != measure.marks => Err(format!(
+            "Measure marks of staff {} voice {} do not match another voice (Measure {})",
+            voice.staff, voice.number, number
+        )),
+        Some(measure) => {
+            measure.voices.push(voice);
+            measure.voices.sort_by_key(|v| (v.staff, v.number));
+            if marks != MeasureMarks::default() {
+                measure.marks = marks;
+            }
+            Ok(())
+        }
+        None => {
+            measures.push(Measure {
+                number,
+                voices: vec![voice],
+                duration: 0.0,
+                unit_duration: 0.0,
+                length,
+                marks,
+                meter,
+                beat_groups,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// 小節全体の休符 `R` の拍（拍子の全単位を占める1つの休符）
+fn measure_rest_beat(id_gen: &mut IdGenerator, beat_groups: &[usize]) -> Beat {
+    let rest = Event {
+        id: Some(id_gen.next_id()),
+        anchored: false,
+        event_type: EventType::Rest,
+        pitch: None,
+        pitch_cents: None,
+        tie: false,
+        tied_from: None,
+        slide: false,
+        grace_notes: Vec::new(),
+        articulations: Vec::new(),
+        dynamic: None,
+        unpitched: None,
+        duration: num_rational::Ratio::from_integer(1),
+    };
+    Beat { elements: vec![ScoreElement::Event(rest)], duration: 0.0, units: beat_groups.iter().sum() }
+}
+
+/// 小節記号を、小節の始まりに付くもの（`|:`・括弧・セーニョ・コーダ・テンポ・調）と終わりに付くものに分ける
+fn split_marks(marks: MeasureMarks) -> (MeasureMarks, MeasureMarks) {
+    let start = MeasureMarks {
+        repeat_start: marks.repeat_start,
+        volta: marks.volta,
+        segno: marks.segno,
+        coda: marks.coda,
+        tempo: marks.tempo,
+        key: marks.key,
+        ..MeasureMarks::default()
+    };
+    let end = MeasureMarks {
+        repeat_end: marks.repeat_end,
+        to_coda: marks.to_coda,
+        fine: marks.fine,
+        jump: marks.jump,
+        ..MeasureMarks::default()
+    };
+    (start, end)
+}
+
+/// 1パートあたりの最大譜表数
+const MAX_STAFF_COUNT: usize = 8;
+
+/// `#[Drum(...)]` の中身を読む。名前の後に `key=56`（MIDIキー、必須）、`display=E5`（譜表上の位置、省略時はC5）、
+/// `notehead=triangle`（省略時はnormal）を書ける。
+fn parse_drum_definition(spec: &str, line: &str) -> Result<DrumSound, Vec<ParseError>> {
+    const KEYS: &[&str] = &["key", "display", "notehead"];
+    let mut items = spec.split(',');
+    let name = items.next().unwrap_or("").trim();
+    let mut errors = Vec::new();
+    let mut error = |message: String, at: &str| {
+        errors.push(ParseError { message, line: None, span: Some(span_of(line, at)), file: None });
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.')
+        && !name.ends_with('.')
+        && !name.split('.').any(|part| part.is_empty() || Articulation::from_spelling(part).is_some())
+        && !["t", "r"].contains(&name);
+    if !valid_name {
+        error(format!(
+            "Invalid drum name '{}': use lowercase letters, digits, '_' and '.' (not 't', 'r' or an articulation name)",
+            name
+        ), spec.trim());
+        return Err(errors);
+    }
+    let mut drum = DrumSound { name: name.to_string(), key: 0, display: Pitch::Midi(72), notehead: "normal".to_string() };
+    let mut has_key = false;
+    for item in items {
+        let item = item.trim();
+        let Some((key, value)) = item.split_once('=').map(|(k, v)| (k.trim(), v.trim())) else {
+            error(format!("Drum attribute '{}' must be written as key=value", item), item);
+            continue;
+        };
+        match key {
+            "key" => match value.parse::<u8>() {
+                Ok(n) if n <= 127 => {
+                    drum.key = n;
+                    has_key = true;
+                }
+                _ => error(format!("Invalid MIDI key '{}': expected an integer from 0 to 127", value), item),
+            },
+            "display" => match value.parse::<Pitch>() {
+                Ok(pitch) => drum.display = pitch,
+                Err(e) => error(format!("Invalid display position '{}': {}", value, e), item),
+            },
+            "notehead" if NOTEHEADS.contains(&value) => drum.notehead = value.to_string(),
+            "notehead" => error(format!(
+                "Unknown notehead '{}'{}",
+                value,
+                hint(did_you_mean(value, NOTEHEADS.iter().copied())),
+            ), item),
+            _ => error(format!("Unknown drum attribute '{}'{}", key, hint(did_you_mean(key, KEYS.iter().copied()))), item),
+        }
+    }
+    if !has_key && errors.is_empty() {
+        return Err(vec![ParseError {
+            message: format!("Drum '{}' needs a MIDI key (e.g. key=56)", name),
+            line: None,
+            span: Some(span_of(line, spec.trim())),
+            file: None,
+        }]);
+    }
+    if errors.is_empty() {
+        Ok(drum)
+    } else {
+        Err(errors)
+    }
+}
+
+/// `#[Lyrics]` ブロックの歌詞を付ける譜表・声部と番、直前の音節が `-` で続いているか
+struct LyricsBlock {
+    staff: usize,
+    voice: usize,
+    verse: usize,
+    hyphen: bool,
+}
+
+/// `#[Lyrics(...)]` の中身を読む。`voice=2`（省略時は現在の声部）と `verse=2`（省略時は1番）を書ける。
+fn parse_lyrics_header(spec: &str, line: &str, staff: usize, voice: usize) -> Result<LyricsBlock, Vec<ParseError>> {
+    const KEYS: &[&str] = &["voice", "verse"];
+    let mut block = LyricsBlock { staff, voice, verse: 1, hyphen: false };
+    let mut errors = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let error = |message: String| ParseError { message, line: None, span: Some(span_of(line, item)), file: None };
+        let Some((key, value)) = item.split_once('=').map(|(k, v)| (k.trim(), v.trim())) else {
+            errors.push(error(format!("Lyrics attribute '{}' must be written as key=value", item)));
+            continue;
+        };
+        let number = value.parse::<usize>().ok().filter(|&n| n > 0);
+        match (key, number) {
+            ("voice", Some(n)) => block.voice = n,
+            ("verse", Some(n)) => block.verse = n,
+            ("voice" | "verse", None) => {
+                errors.push(error(format!("Invalid {} number '{}': expected an integer of 1 or more", key, value)));
+            }
+            _ => errors.push(error(format!(
+                "Unknown lyrics attribute '{}'{}",
+                key,
+                hint(did_you_mean(key, KEYS.iter().copied())),
+            ))),
+        }
+    }
+    if errors.is_empty() {
+        Ok(block)
+    } else {
+        Err(errors)
+    }
+}
+
+/// 歌詞を付ける音（音符・和音）のid。休符・タイ・`-` で前の音から続く音には付けない
+pub(crate) fn lyric_targets(elements: &[ScoreElement]) -> Vec<u64> {
+    let mut leaves = Vec::new();
+    collect_leaves(elements, &mut leaves);
+    leaves.into_iter()
+        .filter_map(|leaf| match leaf {
+            ScoreElement::Event(ev) if ev.event_type == EventType::Note && ev.tied_from.is_none() => ev.id,
+            ScoreElement::Chord(chord) if chord.events.iter().any(|ev| ev.tied_from.is_none()) => chord.id,
+            _ => None,
+        })
+        .collect()
+}
+
+/// 歌詞の行 `N: Hel- lo world_ _` を読み、小節の音に順に音節を割り当てる。
+/// 音節の後の `-` は次の音節へ続くハイフン、`_` は延ばす線で、`_` だけなら音節を付けずに次の音へ進む。
+fn parse_lyrics_line(
+    line: &str,
+    line_content: &str,
+    block: &mut LyricsBlock,
+    measures: &[Measure],
+    lyrics: &mut Vec<Lyric>,
+) -> Result<(), Vec<ParseError>> {
+    let error = |message: String, at: &str| ParseError { message, line: None, span: Some(span_of(line_content, at)), file: None };
+    let Some((number, text)) = line.split_once(':') else {
+        return Err(vec![error("Lyrics line must start with a measure number (e.g. `1: Hel- lo`)".to_string(), line)]);
+    };
+    let Ok(measure_no) = number.trim().parse::<usize>() else {
+        return Err(vec![error(format!("Invalid measure number '{}' in lyrics", number.trim()), number.trim())]);
+    };
+    let voice = measures.iter()
+        .find(|m| m.number == measure_no)
+        .and_then(|m| m.voices.iter().find(|v| v.staff == block.staff && v.number == block.voice));
+    let Some(voice) = voice else {
+        return Err(vec![error(
+            format!("Lyrics for measure {}: staff {} voice {} has no such measure", measure_no, block.staff, block.voice),
+            number.trim(),
+        )]);
+    };
+    let elements: Vec<ScoreElement> = voice.beats.iter().flat_map(|b| b.elements.iter().cloned()).collect();
+    let targets = lyric_targets(&elements);
+    let mut errors = Vec::new();
+    for (i, syllable) in text.split_whitespace().enumerate() {
+        let Some(&id) = targets.get(i) else {
+            errors.push(error(
+                format!("Measure {} has only {} note(s) to carry lyrics in staff {} voice {}", measure_no, targets.len(), block.staff, block.voice),
+                syllable,
+            ));
+            break;
+        };
+        if syllable == "_" {
+            continue;
+        }
+        let extender = syllable.ends_with('_');
+        let word = syllable.trim_end_matches('_');
+        let hyphen = word.ends_with('-');
+        let word = word.trim_end_matches('-');
+        if word.is_empty() {
+            errors.push(error(format!("Invalid syllable '{}'", syllable), syllable));
+            continue;
+        }
+        let note = NoteRef { measure: measure_no, id };
+        if lyrics.iter().any(|l| l.note == note && l.verse == block.verse) {
+            errors.push(error(format!("Verse {} already has lyrics on this note (Measure {})", block.verse, measure_no), syllable));
+            continue;
+        }
+        lyrics.push(Lyric {
+            note,
+            staff: block.staff,
+            voice: block.voice,
+            verse: block.verse,
+            text: word.to_string(),
+            syllabic: Syllabic::from_hyphens(block.hyphen, hyphen),
+            extender,
+        });
+        block.hyphen = hyphen;
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// 属性のないパートのヘッダー
+fn new_part(name: String) -> Part {
+    Part {
+        name,
+        instrument: None,
+        clefs: Vec::new(),
+        transposition: None,
+        staff_count: 0,
+        measures: Vec::new(),
+        lyrics: Vec::new(),
+    }
+}
+
+/// `#[Part(...)]` の中身を読む。パート名の後に `instrument=clarinet_bb`（組み込みの楽器名）, `clef=treble+bass`,
+/// `transpose=-2` をカンマ区切りで書ける。
+fn parse_part_header(spec: &str, line: &str) -> Result<Part, Vec<ParseError>> {
+    const KEYS: &[&str] = &["instrument", "clef", "transpose"];
+    let mut items = spec.split(',');
+    let name = items.next().unwrap_or("").trim();
+    let mut part = new_part(name.to_string());
+    let mut errors = Vec::new();
+    let mut error = |message: String, at: &str| {
+        errors.push(ParseError { message, line: None, span: Some(span_of(line, at)), file: None });
+    };
+    if name.is_empty() || name.contains('=') {
+        error("Part header must start with the part name (e.g. `#[Part(Piano)]`)".to_string(), spec.trim());
+        return Err(errors);
+    }
+    let mut seen: Vec<&str> = Vec::new();
+    for item in items {
+        let item = item.trim();
+        let Some((key, value)) = item.split_once('=').map(|(k, v)| (k.trim(), v.trim())) else {
+            error(format!("Part attribute '{}' must be written as key=value", item), item);
+            continue;
+        };
+        if seen.contains(&key) {
+            error(format!("Part attribute '{}' is given more than once", key), item);
+            continue;
+        }
+        seen.push(key);
+        match key {
+            "instrument" if INSTRUMENTS.iter().any(|(name, _, _)| *name == value) => part.instrument = Some(value.to_string()),
+            "instrument" if !value.is_empty() => error(format!(
+                "Unknown instrument '{}'{}",
+                value,
+                hint(did_you_mean(value, INSTRUMENTS.iter().map(|(name, _, _)| *name))),
+            ), item),
+            "clef" => {
+                for clef in value.split('+').map(str::trim) {
+                    match CLEFS.iter().find(|(spelling, _)| *spelling == clef) {
+                        Some((_, name)) => part.clefs.push(name.to_string()),
+                        None => error(format!(
+                            "Unknown clef '{}'{}",
+                            clef,
+                            hint(did_you_mean(&clef.to_lowercase(), CLEFS.iter().map(|(spelling, _)| *spelling))),
+                        ), item),
+                    }
+                }
+                if part.clefs.len() > MAX_STAFF_COUNT {
+                    error(format!("A part can have at most {} clefs", MAX_STAFF_COUNT), item);
+                }
+            }
+            "transpose" => match value.parse::<i32>() {
+                Ok(n) => part.transposition = Some(n),
+                Err(_) => error(format!("Invalid transposition '{}': expected semitones such as -2", value), item),
+            },
+            "instrument" => error("Part attribute 'instrument' needs a value".to_string(), item),
+            _ => error(format!("Unknown part attribute '{}'{}", key, hint(did_you_mean(key, KEYS.iter().copied()))), item),
+        }
+    }
+    if errors.is_empty() {
+        Ok(part)
+    } else {
+        Err(errors)
+    }
+}
+
+/// パートを組み立てる。譜表数は使用された最大の譜表番号（`clef=` で譜表ごとに書いた数の方が多ければその数）とする。
+fn build_part(mut header: Part, mut measures: Vec<Measure>, id_gens: &HashMap<usize, IdGenerator>) -> Result<Part, String> {
+    // `@N` で固定したIDを反映し、残りのIDを振り直す
+    let final_ids: HashMap<usize, HashMap<u64, u64>> = id_gens.iter()
+        .map(|(&number, id_gen)| (number, id_gen.final_ids()))
+        .collect();
+    for measure in &mut measures {
+        for voice in &mut measure.voices {
+            for beat in &mut voice.beats {
+                apply_final_ids(measure.number, &mut beat.elements, &final_ids);
+            }
+        }
+    }
+    for lyric in &mut header.lyrics {
+        if let Some(&final_id) = final_ids.get(&lyric.note.measure).and_then(|ids| ids.get(&lyric.note.id)) {
+            lyric.note.id = final_id;
+        }
+    }
+    let staff_count = measures.iter()
+        .flat_map(|m| m.voices.iter().map(|v| v.staff))
+        .max()
+        .unwrap_or(1);
+    if !header.clefs.is_empty() && header.clefs.len() < staff_count {
+        return Err(format!(
+            "Part '{}' uses staff {} but its header lists {} clef(s)",
+            header.name, staff_count, header.clefs.len(),
+        ));
+    }
+    let staff_count = staff_count.max(header.clefs.len());
+    Ok(Part { staff_count, measures, ..header })
+}
+
+/// 要素のID・タイの接続元のIDを最終的なIDに置き換える
+fn apply_final_ids(measure: usize, elements: &mut [ScoreElement], final_ids: &HashMap<usize, HashMap<u64, u64>>) {
+    let remap = |measure: usize, id: &mut u64| {
+        if let Some(&final_id) = final_ids.get(&measure).and_then(|ids| ids.get(id)) {
+            *id = final_id;
+        }
+    };
+    let remap_event = |ev: &mut Event| {
+        ev.id.iter_mut().for_each(|id| remap(measure, id));
+        ev.tied_from.iter_mut().for_each(|r| remap(r.measure, &mut r.id));
+        for grace in &mut ev.grace_notes {
+            grace.id.iter_mut().for_each(|id| remap(measure, id));
+        }
+    };
+    for elem in elements {
+        match elem {
+            ScoreElement::Event(ev) => remap_event(ev),
+            ScoreElement::Tie(tie) => {
+                tie.id.iter_mut().for_each(|id| remap(measure, id));
+                tie.tied_from.iter_mut().for_each(|r| remap(r.measure, &mut r.id));
+            }
+            ScoreElement::Chord(chord) => {
+                chord.id.iter_mut().for_each(|id| remap(measure, id));
+                chord.events.iter_mut().for_each(remap_event);
+            }
+            ScoreElement::Subdivision(sub) => apply_final_ids(measure, &mut sub.elements, final_ids),
+        }
+    }
+}
+
+/// `sub` が `line` の部分文字列であるとき、その行内での Span を返す
+fn span_of(line: &str, sub: &str) -> Span {
+    let start = sub.as_ptr() as usize - line.as_ptr() as usize;
+    Span::new(start, start + sub.len())
+}
+
+/// 小節内容の先頭にある `pickup` / `partial(n)` を切り出す
+fn split_length_tag(content: &str) -> (Option<&str>, &str) {
+    if content.starts_with("pickup") || content.starts_with("partial(") {
+        let end = content.find(|c: char| c.is_whitespace() || c == '[').unwrap_or(content.len());
+        let (tag, rest) = content.split_at(end);
+        return (Some(tag), rest.trim_start());
+    }
+    (None, content)
+}
+
+/// `pickup` / `partial(n)` を MeasureLength に変換
+fn parse_length_tag(tag: &str) -> Result<MeasureLength, String> {
+    if tag == "pickup" {
+        return Ok(MeasureLength::Pickup);
+    }
+    let n = tag.strip_prefix("partial(").and_then(|t| t.strip_suffix(')'));
+    match n.map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(n)) if n > 0 => Ok(MeasureLength::Partial(n)),
+        Some(_) => Err(format!("Invalid partial measure length in '{}': expected an integer of 1 or more", tag)),
+        None => Err(format!("Invalid measure length '{}': expected 'pickup' or 'partial(n)'", tag)),
+    }
+}
+
+/// `|:` `:|x2` `volta(1,2)` `segno` `D.C.al_Fine` などの小節記号を1つ読み取る
+/// `units` は小節の拍子の単位数（`tempo=72@3` の位置の範囲の検査に使う）
+fn parse_measure_mark(word: &str, units: usize, marks: &mut MeasureMarks) -> Result<(), String> {
+    if let Some((name, spec)) = word.split_once('=') {
+        let (value, position) = match spec.split_once('@') {
+            Some((value, at)) => (value, parse_mark_position(at, units)?),
+            None => (spec, num_rational::Ratio::from_integer(1)),
+        };
+        match name {
+            "tempo" => {
+                let bpm = value.parse::<u32>().ok().filter(|&bpm| bpm > 0)
+                    .ok_or_else(|| format!("Invalid tempo '{}': expected beats per minute such as 96", value))?;
+                if marks.tempo.iter().any(|t| t.position == position) {
+                    return Err(format!("More than one tempo at the same position: '{}'", word));
+                }
+                marks.tempo.push(TempoChange { position, bpm });
+            }
+            "key" => {
+                let Some((_, key)) = KEYS.iter().find(|(spelling, _)| *spelling == value) else {
+                    let suggestion = did_you_mean(value, KEYS.iter().map(|(spelling, _)| *spelling));
+                    return Err(format!("Unknown key '{}'{} (write e.g. Eb for E-flat major, C#m for C-sharp minor)", value, hint(suggestion)));
+                };
+                if marks.key.iter().any(|k| k.position == position) {
+                    return Err(format!("More than one key at the same position: '{}'", word));
+                }
+                marks.key.push(KeyChange { position, key: key.to_string() });
+            }
+            _ => {
+                let suggestion = did_you_mean(name, ["tempo", "key"].into_iter());
+                return Err(format!("Unknown measure directive '{}'{}", name, hint(suggestion)));
+            }
+        }
+        return Ok(());
+    }
+    if let Some(jump) = Jump::from_spelling(word) {
+        if marks.jump.is_some() {
+            return Err(format!("More than one D.C./D.S. mark: '{}'", word));
+        }
+        marks.jump = Some(jump);
+        return Ok(());
+    }
+    match word {
+        "|:" => marks.repeat_start = true,
+        ":|" => marks.repeat_end = Some(2),
+        "segno" => marks.segno = true,
+        "coda" => marks.coda = true,
+        "to_coda" => marks.to_coda = true,
+        "fine" => marks.fine = true,
+        _ => {
+            if let Some(times) = word.strip_prefix(":|x") {
+                match times.parse::<usize>() {
+                    Ok(n) if n >= 2 => marks.repeat_end = Some(n),
+                    _ => return Err(format!("Invalid repeat count in '{}': expected an integer of 2 or more", word)),
+                }
+            } else if let Some(list) = word.strip_prefix("volta(").and_then(|w| w.strip_suffix(')')) {
+                for n in list.split(',') {
+                    match n.trim().parse::<usize>() {
+                        Ok(n) if n > 0 => marks.volta.push(n),
+                        _ => return Err(format!("Invalid volta number in '{}': expected integers of 1 or more", word)),
+                    }
+                }
+            } else {
+                let marks = ["|:", ":|", "segno", "coda", "to_coda", "fine"].into_iter()
+                    .chain(Jump::SPELLINGS.iter().map(|(spelling, _)| *spelling));
+                return Err(format!("Unknown measure mark '{}'{}", word, hint(did_you_mean(word, marks).map(str::to_string))));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `tempo=72@3` の位置（拍子の単位で1始まり、`@2.5` のように小数も書ける）
+fn parse_mark_position(s: &str, units: usize) -> Result<num_rational::Ratio<i32>, String> {
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    let digits = |t: &str| t.chars().all(|c| c.is_ascii_digit());
+    let position = if !whole.is_empty() && digits(whole) && digits(fraction) && fraction.len() <= 6 {
+        let scale = 10i32.pow(fraction.len() as u32);
+        let number = format!("{}{}", whole, fraction).parse::<i32>().ok();
+        number.map(|n| num_rational::Ratio::new(n, scale))
+    } else {
+        None
+    };
+    match position {
+        Some(p) if p >= num_rational::Ratio::from_integer(1) && p < num_rational::Ratio::from_integer(units as i32 + 1) => Ok(p),
+        _ => Err(format!("Invalid position '@{}': expected a beat position from 1 to less than {}", s, units + 1)),
+    }
+}
+
+/// "4/4" や "2+2+3/8" のような文字列を ((分子, 分母), 拍ごとの分母単位数) に変換。
+/// `+` で区切らない場合、6/8・9/8・12/8 のような複合拍子は3単位ずつの拍にまとめる。
+fn parse_meter(s: &str) -> Option<((usize, usize), Vec<usize>)> {
+    let (num, denom) = s.split_once('/')?;
+    let denom: usize = denom.parse().ok()?;
+    let groups: Vec<usize> = num.split('+')
+        .map(|n| n.parse().ok().filter(|&n| n > 0))
+        .collect::<Option<_>>()?;
+    let numerator: usize = groups.iter().sum();
+    let groups = if groups.len() > 1 {
+        groups
+    } else if numerator > 3 && numerator.is_multiple_of(3) {
+        vec![3; numerator / 3]
+    } else {
+        vec![1; numerator]
+    };
+    Some(((numerator, denom), groups))
+}
+
+/// メッセージ用の拍子表記（"4/4"、"2+2+3/8"）
+pub(crate) fn format_meter(meter: (usize, usize), groups: &[usize]) -> String {
+    if groups.iter().all(|&g| g == 1) || groups.iter().all(|&g| g == 3) {
+        format!("{}/{}", meter.0, meter.1)
+    } else {
+        let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+        format!("{}/{}", groups.join("+"), meter.1)
+    }
+}
+
+/// 書かれた拍数から、各拍が占める拍子の分母単位数を決める。
+/// 複合・加法拍子の小節は拍のまとまりごとに書くが、拍子どおりの小節は分母単位ごとに書いてもよい。
+/// 弱起は小節の後ろの拍、不完全小節は前の拍から数える。弱起は拍のまとまりの数より多ければ分母単位ごとに数える。
+fn beat_units(count: usize, meter: (usize, usize), groups: &[usize], length: MeasureLength) -> Result<Vec<usize>, String> {
+    let grouped = groups.len() != meter.0;
+    match length {
+        MeasureLength::Full if count == groups.len() => Ok(groups.to_vec()),
+        MeasureLength::Full if count == meter.0 => Ok(vec![1; count]),
+        MeasureLength::Full if grouped => Err(format!(
+            "Number of beats ({}) does not match meter {} ({} beats, or {} when written per 1/{})",
+            count, format_meter(meter, groups), groups.len(), meter.0, meter.1
+        )),
+        MeasureLength::Full => Err(format!(
+            "Number of beats ({}) does not match meter ({})",
+            count, meter.0
+        )),
+        MeasureLength::Partial(n) if count != n => Err(format!(
+            "Number of beats ({}) does not match partial measure length ({})",
+            count, n
+        )),
+        MeasureLength::Partial(_) if count <= groups.len() => Ok(groups[..count].to_vec()),
+        // 弱起と同じく、拍のまとまりより多い拍は分母単位ごとに数える（長さは拍子の単位数までに検査済み）
+        MeasureLength::Partial(_) => Ok(vec![1; count]),
+        MeasureLength::Pickup if count <= groups.len() => Ok(groups[groups.len() - count..].to_vec()),
+        // 拍のまとまりより多く書いた弱起は分母単位ごとに数える（6/8 の `[r, r, G4]` は8分音符3つ）
+        MeasureLength::Pickup if count <= meter.0 => Ok(vec![1; count]),
+        MeasureLength::Pickup if grouped => Err(format!(
+            "Number of beats ({}) exceeds meter {} ({} beats, or {} when written per 1/{}) in pickup measure",
+            count, format_meter(meter, groups), groups.len(), meter.0, meter.1
+        )),
+        MeasureLength::Pickup => Err(format!(
+            "Number of beats ({}) exceeds meter ({}) in pickup measure",
+            count, meter.0
+        )),
+    }
 }
@@ -0,0 +1,181 @@
+// 記譜順の小節を演奏順に展開する（MIDI・音声出力やタイミング計算用）
+use crate::data::{Measure, Score};
+
+/// 反復記号・括弧・D.C./D.S.に従って、演奏順の小節インデックス列を返す
+///
+/// D.C./D.S.で戻った後は反復をせず、括弧は最後の番号のものだけを演奏する。
+/// D.C./D.S.は一度だけ実行する。
+pub fn performance_order(measures: &[Measure]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut i = 0;
+    let mut section_start = 0; // 現在の反復区間の始まり
+    let mut pass = 1; // 現在の反復区間で何回目の演奏か
+    let mut jumped = false;
+    while i < measures.len() {
+        let marks = &measures[i].marks;
+        // `|:` または括弧の終わりから次の反復区間が始まる
+        let after_volta = marks.volta.is_empty() && i > 0 && !measures[i - 1].marks.volta.is_empty();
+        if i != section_start && (marks.repeat_start || after_volta) {
+            pass = 1;
+            section_start = i;
+        }
+        if !marks.volta.is_empty() {
+            let plays = if jumped {
+                marks.volta.contains(&last_volta(measures, i))
+            } else {
+                marks.volta.contains(&pass)
+            };
+            if !plays {
+                i += 1;
+                continue;
+            }
+        }
+        order.push(i);
+
+        if jumped && marks.fine {
+            break;
+        }
+        if jumped && marks.to_coda {
+            if let Some(coda) = (i + 1..measures.len()).find(|&j| measures[j].marks.coda) {
+                i = coda;
+                continue;
+            }
+        }
+        if let (Some(times), false) = (marks.repeat_end, jumped) {
+            if pass < times {
+                pass += 1;
+                i = section_start;
+                continue;
+            }
+            pass = 1;
+            section_start = i + 1;
+        }
+        if let (Some(jump), false) = (marks.jump, jumped) {
+            jumped = true;
+            pass = 1;
+            i = if jump.returns_to_segno() {
+                measures.iter().position(|m| m.marks.segno).unwrap_or(0)
+            } else {
+                0
+            };
+            section_start = i;
+            continue;
+        }
+        i += 1;
+    }
+    order
+}
+
+/// i番目の小節を含む、連続した括弧の中で最大の番号
+fn last_volta(measures: &[Measure], i: usize) -> usize {
+    let has_volta = |j: &usize| !measures[*j].marks.volta.is_empty();
+    let start = (0..=i).rev().take_while(has_volta).last().unwrap_or(i);
+    let end = (i..measures.len()).take_while(has_volta).last().unwrap_or(i);
+    measures[start..=end].iter()
+        .flat_map(|m| m.marks.volta.iter().copied())
+        .max()
+        .unwrap_or(1)
+}
+
+/// スコア全体を演奏順に展開する（小節番号は記譜上の番号のまま）
+pub fn unroll_score(score: &Score) -> Score {
+    let mut unrolled = score.clone();
+    for part in &mut unrolled.parts {
+        part.measures = performance_order(&part.measures).into_iter()
+            .map(|i| part.measures[i].clone())
+            .collect();
+    }
+    unrolled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_score;
+
+    /// 1/4拍子の1パートのVSC（1行1小節、`[C5]` の前後に記号を書く）を展開した小節番号の列
+    fn unrolled(measures: &[(&str, &str)]) -> Vec<usize> {
+        let mut src = String::from("#[Part(A)]\n");
+        for (i, (start, end)) in measures.iter().enumerate() {
+            let meter = if i == 0 { "1/4 " } else { "" };
+            src.push_str(&format!("{}: {}{} [C5] {}\n", i + 1, meter, start, end));
+        }
+        let score = parse_score(&src).unwrap_or_else(|e| panic!("{:?}", e));
+        unroll_score(&score).parts[0].measures.iter().map(|m| m.number).collect()
+    }
+
+    #[test]
+    fn repeat_plays_section_twice() {
+        assert_eq!(unrolled(&[("|:", ""), ("", ":|"), ("", "")]), vec![1, 2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn repeat_count_plays_section_n_times() {
+        assert_eq!(unrolled(&[("", ""), ("|:", ":|x3"), ("", "")]), vec![1, 2, 2, 2, 3]);
+    }
+
+    #[test]
+    fn repeat_without_start_returns_to_beginning() {
+        assert_eq!(unrolled(&[("", ""), ("", ":|"), ("", "")]), vec![1, 2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn volta_endings_alternate() {
+        let measures = [("|:", ""), ("volta(1)", ""), ("volta(1)", ":|"), ("volta(2)", ""), ("volta(2)", ""), ("", "")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 3, 1, 4, 5, 6]);
+    }
+
+    #[test]
+    fn volta_with_several_passes() {
+        let measures = [("|:", ""), ("volta(1,2)", ":|x3"), ("volta(3)", ""), ("", "")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 1, 2, 1, 3, 4]);
+    }
+
+    #[test]
+    fn consecutive_repeats_with_voltas() {
+        let measures = [
+            ("|:", ""), ("volta(1)", ":|"), ("volta(2)", ""),
+            ("|:", ""), ("volta(1)", ":|"), ("volta(2)", ""),
+        ];
+        assert_eq!(unrolled(&measures), vec![1, 2, 1, 3, 4, 5, 4, 6]);
+    }
+
+    #[test]
+    fn da_capo_returns_to_beginning_once() {
+        assert_eq!(unrolled(&[("", ""), ("", "D.C.")]), vec![1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn da_capo_al_fine_stops_at_fine() {
+        assert_eq!(unrolled(&[("", ""), ("", "fine"), ("", "D.C.al_Fine")]), vec![1, 2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn da_capo_al_coda_jumps_to_coda() {
+        let measures = [("", "to_coda"), ("", "D.C.al_Coda"), ("coda", ""), ("", "")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 1, 3, 4]);
+    }
+
+    #[test]
+    fn dal_segno_returns_to_segno() {
+        assert_eq!(unrolled(&[("", ""), ("segno", ""), ("", "D.S.")]), vec![1, 2, 3, 2, 3]);
+    }
+
+    #[test]
+    fn dal_segno_al_fine_stops_at_fine() {
+        let measures = [("", ""), ("segno", ""), ("", "fine"), ("", "D.S.al_Fine")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 3, 4, 2, 3]);
+    }
+
+    #[test]
+    fn dal_segno_al_coda_skips_to_coda() {
+        let measures = [("", ""), ("segno", ""), ("", "to_coda"), ("", "D.S.al_Coda"), ("coda", ""), ("", "")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 3, 4, 2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn jump_skips_repeats_and_takes_last_volta() {
+        let measures = [("|:", ""), ("volta(1)", ":|"), ("volta(2)", ""), ("", "D.C.")];
+        assert_eq!(unrolled(&measures), vec![1, 2, 1, 3, 4, 1, 3, 4]);
+    }
+}
@@ -0,0 +1,425 @@
+// Score を正規化したVSCテキストに書き出す（整形や、他のツールからのVSC生成用）
+use crate::data::{
+    Score, Part, Measure, Beat, ScoreElement, Event, EventType, Tie, Chord, Pitch, NoteRef,
+    MeasureLength, MeasureMarks, GraceKind, Articulation, DrumSound, Unpitched, CLEFS, KEYS,
+};
+use crate::parser::{format_meter, lyric_targets, remove_comments_multiline};
+use num_rational::Ratio;
+use std::collections::HashMap;
+
+/// 解析直後の Score を VSC テキストにする。`parse_score` で読み直すと同じ Score になる。
+///
+/// パートごとに `#[Part(...)]`、譜表・声部ごとに `#[Staff(n)]`・`#[Voice(n)]` を書き、
+/// 拍子は声部の中で変わる小節にだけ書く。拍は声部ごとに桁をそろえる。
+/// モチーフ・include・相対オクターブ入力は展開された形で書き出される。
+/// 組み込みと異なる打楽器の音は、使うパートの前に `#[Drum(...)]` で定義する。
+pub fn print_score(score: &Score) -> String {
+    let mut drums = DrumSound::general_midi();
+    let blocks: Vec<String> = score.parts.iter()
+        .map(|part| print_drums(part, &mut drums) + &print_part(part))
+        .collect();
+    blocks.join("\n")
+}
+
+/// `print_score` が展開して元の書き方を残さない構文（include・モチーフ・相対オクターブ入力）を使う行。
+/// 0始まりの行番号と構文の名前を返す（整形結果で元のファイルを上書きしてよいかの判断用）。
+pub fn expanded_syntax(input: &str) -> Vec<(usize, &'static str)> {
+    remove_comments_multiline(input).lines().enumerate().filter_map(|(i, line)| {
+        let line = line.trim();
+        let name = if line.starts_with("#include") {
+            "#include"
+        } else if line.starts_with("let ") {
+            "motif definition `let`"
+        } else if line == "#[Relative]" || line.starts_with("#[Relative(") {
+            "#[Relative]"
+        } else {
+            return None;
+        };
+        Some((i, name))
+    }).collect()
+}
+
+/// パートで使う打楽器の音のうち、`drums`（それまでのドラムマップ）と異なるものの `#[Drum(...)]`
+fn print_drums(part: &Part, drums: &mut Vec<DrumSound>) -> String {
+    fn collect<'a>(elements: &'a [ScoreElement], out: &mut Vec<&'a Unpitched>) {
+        for elem in elements {
+            match elem {
+                ScoreElement::Event(ev) => out.extend(ev.unpitched.as_ref()),
+                ScoreElement::Chord(chord) => out.extend(chord.events.iter().filter_map(|ev| ev.unpitched.as_ref())),
+                ScoreElement::Subdivision(sub) => collect(&sub.elements, out),
+                ScoreElement::Tie(_) => {}
+            }
+        }
+    }
+    let mut used = Vec::new();
+    for beat in part.measures.iter().flat_map(|m| m.voices.iter()).flat_map(|v| v.beats.iter()) {
+        collect(&beat.elements, &mut used);
+    }
+    let mut out = String::new();
+    for sound in used {
+        let known = drums.iter().find(|d| d.name == sound.name);
+        let same = known.is_some_and(|d| {
+            d.key == sound.key && d.display.to_string() == sound.display.to_string() && d.notehead == sound.notehead
+        });
+        if same {
+            continue;
+        }
+        out.push_str(&format!(
+            "#[Drum({}, key={}, display={}, notehead={})]\n",
+            sound.name, sound.key, sound.display, sound.notehead
+        ));
+        let drum = DrumSound {
+            name: sound.name.clone(),
+            key: sound.key,
+            display: sound.display.clone(),
+            notehead: sound.notehead.clone(),
+        };
+        match drums.iter_mut().find(|d| d.name == drum.name) {
+            Some(existing) => *existing = drum,
+            None => drums.push(drum),
+        }
+    }
+    out
+}
+
+fn print_part(part: &Part) -> String {
+    let dash_ties = dash_tied_notes(part);
+    let mut out = format!("#[Part({})]\n", format_part_header(part));
+    let mut voices: Vec<(usize, usize)> = part.measures.iter()
+        .flat_map(|m| m.voices.iter().map(|v| (v.staff, v.number)))
+        .collect();
+    voices.sort();
+    voices.dedup();
+    for (staff, voice) in voices {
+        if part.staff_count > 1 && voice == 1 {
+            out.push_str(&format!("#[Staff({})]\n", staff));
+        }
+        if voice > 1 {
+            out.push_str(&format!("#[Voice({})]\n", voice));
+        }
+        let lines: Vec<Line> = voice_lines(part, staff, voice, &dash_ties);
+        out.push_str(&align_lines(&lines));
+        out.push_str(&print_lyrics(part, staff, voice));
+    }
+    out
+}
+
+/// 声部の歌詞を番ごとに `#[Lyrics]` ブロックとして書く。音節を付けない音は `_` にする
+fn print_lyrics(part: &Part, staff: usize, voice: usize) -> String {
+    let mut verses: Vec<usize> = part.lyrics.iter()
+        .filter(|l| l.staff == staff && l.voice == voice)
+        .map(|l| l.verse)
+        .collect();
+    verses.sort();
+    verses.dedup();
+    let mut out = String::new();
+    for verse in verses {
+        out.push_str(&if verse == 1 { "#[Lyrics]\n".to_string() } else { format!("#[Lyrics(verse={})]\n", verse) });
+        for measure in &part.measures {
+            let Some(v) = measure.voices.iter().find(|v| v.staff == staff && v.number == voice) else {
+                continue;
+            };
+            let elements: Vec<ScoreElement> = v.beats.iter().flat_map(|b| b.elements.iter().cloned()).collect();
+            let mut syllables: Vec<String> = lyric_targets(&elements).into_iter()
+                .map(|id| {
+                    let note = NoteRef { measure: measure.number, id };
+                    match part.lyrics.iter().find(|l| l.note == note && l.verse == verse && l.staff == staff && l.voice == voice) {
+                        Some(lyric) => format!(
+                            "{}{}{}",
+                            lyric.text,
+                            if lyric.syllabic.has_hyphen() { "-" } else { "" },
+                            if lyric.extender { "_" } else { "" },
+                        ),
+                        None => "_".to_string(),
+                    }
+                })
+                .collect();
+            while syllables.last().is_some_and(|s| s == "_") {
+                syllables.pop();
+            }
+            if !syllables.is_empty() {
+                out.push_str(&format!("{}: {}\n", measure.number, syllables.join(" ")));
+            }
+        }
+    }
+    out
+}
+
+/// パート名と `instrument=`・`clef=`・`transpose=` の属性
+fn format_part_header(part: &Part) -> String {
+    let mut items = vec![part.name.clone()];
+    if let Some(instrument) = &part.instrument {
+        items.push(format!("instrument={}", instrument));
+    }
+    if !part.clefs.is_empty() {
+        let clefs: Vec<&str> = part.clefs.iter()
+            .filter_map(|name| CLEFS.iter().find(|(_, n)| n == name).map(|(spelling, _)| *spelling))
+            .collect();
+        items.push(format!("clef={}", clefs.join("+")));
+    }
+    if let Some(transposition) = part.transposition {
+        items.push(format!("transpose={}", transposition));
+    }
+    items.join(", ")
+}
+
+/// 1小節分の行。桁をそろえる前の各欄
+struct Line {
+    number: String,
+    head: String,
+    beats: Vec<String>,
+    tail: String,
+}
+
+/// 譜表・声部の小節を順に行にする
+fn voice_lines(part: &Part, staff: usize, voice: usize, dash_ties: &HashMap<NoteRef, bool>) -> Vec<Line> {
+    let mut last_meter = None;
+    part.measures.iter()
+        .filter_map(|measure| {
+            let v = measure.voices.iter().find(|v| v.staff == staff && v.number == voice)?;
+            // 複数小節の休符は最初の小節で `12-20: [R]` とまとめて書く
+            let range = v.measure_rest.filter(|rest| rest.count > 1);
+            if range.is_some_and(|rest| rest.first != measure.number) {
+                return None;
+            }
+            let last_measure = range
+                .and_then(|rest| part.measures.iter().find(|m| m.number == rest.first + rest.count - 1))
+                .unwrap_or(measure);
+            // 小節記号は小節の最初の声部にだけ書く（複数小節の休符の終わりの記号は最後の小節のもの）
+            let marks_of = |m: &Measure| {
+                let is_first_voice = m.voices.first().is_some_and(|f| f.staff == staff && f.number == voice);
+                if is_first_voice { m.marks.clone() } else { MeasureMarks::default() }
+            };
+            let meter = (measure.meter, measure.beat_groups.clone());
+            let mut head = Vec::new();
+            if last_meter.as_ref() != Some(&meter) {
+                head.push(format_meter(measure.meter, &measure.beat_groups));
+                last_meter = Some(meter);
+            }
+            match measure.length {
+                MeasureLength::Full => {}
+                MeasureLength::Pickup => head.push("pickup".to_string()),
+                MeasureLength::Partial(n) => head.push(format!("partial({})", n)),
+            }
+            let (start_marks, _) = format_marks(&marks_of(measure));
+            let (_, end_marks) = format_marks(&marks_of(last_measure));
+            head.extend(start_marks);
+            Some(Line {
+                number: match range {
+                    Some(rest) => format!("{}-{}", rest.first, rest.first + rest.count - 1),
+                    None => measure.number.to_string(),
+                },
+                head: head.join(" "),
+                beats: match v.measure_rest {
+                    Some(_) => vec!["R".to_string()],
+                    None => v.beats.iter().map(|b| format_beat(b, dash_ties)).collect(),
+                },
+                tail: end_marks.join(" "),
+            })
+        })
+        .collect()
+}
+
+/// 小節番号・拍子などの欄と、拍ごとの欄の幅をそろえて行を書く
+fn align_lines(lines: &[Line]) -> String {
+    let number_width = lines.iter().map(|l| l.number.len()).max().unwrap_or(0);
+    let head_width = lines.iter().map(|l| l.head.chars().count()).max().unwrap_or(0);
+    let beat_count = lines.iter().map(|l| l.beats.len()).max().unwrap_or(0);
+    let beat_widths: Vec<usize> = (0..beat_count)
+        .map(|i| lines.iter().filter_map(|l| l.beats.get(i)).map(|b| b.chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for line in lines {
+        let mut text = format!("{:>w$}:", line.number, w = number_width);
+        if head_width > 0 {
+            text.push_str(&format!(" {:<w$}", line.head, w = head_width));
+        }
+        let last = line.beats.len().saturating_sub(1);
+        let beats: Vec<String> = line.beats.iter().enumerate()
+            .map(|(i, b)| if i == last { b.clone() } else { format!("{:<w$}", format!("{},", b), w = beat_widths[i] + 1) })
+            .collect();
+        text.push_str(&format!(" [{}]", beats.join(" ")));
+        if !line.tail.is_empty() {
+            text.push(' ');
+            text.push_str(&line.tail);
+        }
+        out.push_str(&text);
+        out.push('\n');
+    }
+    out
+}
+
+/// 小節記号を、拍の前に書くものと後に書くものに分ける
+fn format_marks(marks: &MeasureMarks) -> (Vec<String>, Vec<String>) {
+    let mut start = Vec::new();
+    let mut end = Vec::new();
+    if marks.segno {
+        start.push("segno".to_string());
+    }
+    if marks.coda {
+        start.push("coda".to_string());
+    }
+    if marks.repeat_start {
+        start.push("|:".to_string());
+    }
+    if !marks.volta.is_empty() {
+        let numbers: Vec<String> = marks.volta.iter().map(|n| n.to_string()).collect();
+        start.push(format!("volta({})", numbers.join(",")));
+    }
+    for change in &marks.key {
+        let spelling = KEYS.iter().find(|(_, name)| *name == change.key).map_or("", |(spelling, _)| *spelling);
+        start.push(format!("key={}{}", spelling, format_mark_position(change.position)));
+    }
+    for change in &marks.tempo {
+        start.push(format!("tempo={}{}", change.bpm, format_mark_position(change.position)));
+    }
+    match marks.repeat_end {
+        Some(2) => end.push(":|".to_string()),
+        Some(n) => end.push(format!(":|x{}", n)),
+        None => {}
+    }
+    if marks.to_coda {
+        end.push("to_coda".to_string());
+    }
+    if marks.fine {
+        end.push("fine".to_string());
+    }
+    if let Some(jump) = marks.jump {
+        end.push(jump.spelling().to_string());
+    }
+    (start, end)
+}
+
+/// テンポ・調の小節内の位置。小節頭なら省略し、それ以外は `@2.5` のように書く
+fn format_mark_position(position: Ratio<i32>) -> String {
+    if position == Ratio::from_integer(1) {
+        return String::new();
+    }
+    let value = *position.numer() as f64 / *position.denom() as f64;
+    format!("@{}", value)
+}
+
+fn format_beat(beat: &Beat, dash_ties: &HashMap<NoteRef, bool>) -> String {
+    let elements: Vec<String> = beat.elements.iter().map(|e| format_element(e, dash_ties)).collect();
+    elements.join(" ")
+}
+
+fn format_element(elem: &ScoreElement, dash_ties: &HashMap<NoteRef, bool>) -> String {
+    match elem {
+        ScoreElement::Event(ev) => format_event(ev),
+        ScoreElement::Tie(tie) => format_tie(tie),
+        ScoreElement::Chord(chord) => format_chord(chord, dash_ties),
+        ScoreElement::Subdivision(sub) => {
+            let items: Vec<String> = sub.elements.iter().zip(&sub.weights)
+                .map(|(e, &w)| {
+                    let text = format_element(e, dash_ties);
+                    if w == 1 { text } else { format!("{}*{}", text, w) }
+                })
+                .collect();
+            format!("[{}]", items.join(", "))
+        }
+    }
+}
+
+/// 音符・休符。前打音、音高（セントのずれ）または打楽器の音の名前、奏法、強弱、タイの順に書く
+fn format_event(ev: &Event) -> String {
+    let Some(pitch) = ev.pitch.as_ref().filter(|_| ev.event_type == EventType::Note) else {
+        return format!("r{}", format_anchor(ev.id, ev.anchored));
+    };
+    let mut text = String::new();
+    if !ev.grace_notes.is_empty() {
+        let slash = if ev.grace_notes[0].kind == GraceKind::Acciaccatura { "/" } else { "" };
+        let notes: Vec<String> = ev.grace_notes.iter().map(|g| format_pitch(&g.pitch, g.pitch_cents)).collect();
+        text.push_str(&format!("({}{})", slash, notes.join(" ")));
+    }
+    match &ev.unpitched {
+        Some(unpitched) => text.push_str(&unpitched.name),
+        None => text.push_str(&format_pitch(pitch, ev.pitch_cents)),
+    }
+    for articulation in &ev.articulations {
+        match articulation {
+            Articulation::Accent => text.push('>'),
+            other => text.push_str(&format!(".{}", other.spelling())),
+        }
+    }
+    if let Some(level) = &ev.dynamic {
+        text.push_str(&format!("!{}", level.to_lowercase()));
+    }
+    if ev.tie {
+        text.push('-');
+    }
+    text.push_str(&format_anchor(ev.id, ev.anchored));
+    text
+}
+
+/// `@N` で固定されたID
+fn format_anchor(id: Option<u64>, anchored: bool) -> String {
+    match id {
+        Some(id) if anchored => format!("@{}", id),
+        _ => String::new(),
+    }
+}
+
+fn format_tie(tie: &Tie) -> String {
+    let text = match (&tie.pitch, tie.slide) {
+        (Some(pitch), true) => format!("t({})", format_pitch(pitch, tie.pitch_cents)),
+        _ => "t".to_string(),
+    };
+    text + &format_anchor(tie.id, tie.anchored)
+}
+
+/// 和音。前の和音の同じ位置から続く構成音は `t`（音高を変えるなら `t(...)`）で書く。
+/// ただし `-` 付きの同じ高さの音から続く構成音は音名で書く（読み直すと同じ接続になる）。
+fn format_chord(chord: &Chord, dash_ties: &HashMap<NoteRef, bool>) -> String {
+    let members: Vec<String> = chord.events.iter()
+        .map(|ev| match (ev.tied_from, &ev.pitch) {
+            (Some(_), Some(pitch)) if ev.slide => {
+                format!("t({}){}", format_pitch(pitch, ev.pitch_cents), format_anchor(ev.id, ev.anchored))
+            }
+            (Some(origin), _) if !dash_ties.get(&origin).copied().unwrap_or(false) => {
+                format!("t{}", format_anchor(ev.id, ev.anchored))
+            }
+            _ => format_event(ev),
+        })
+        .collect();
+    format!("{{{}}}{}", members.join(", "), format_anchor(chord.id, chord.anchored))
+}
+
+/// 音高。`pitch_cents` が音名の音高とずれていれば `C5+31c` のように書く
+fn format_pitch(pitch: &Pitch, pitch_cents: Option<u16>) -> String {
+    match (pitch.cents(), pitch_cents) {
+        (Ok(base), Some(cents)) if base != cents => format!("{}{:+}c", pitch, cents as i32 - base as i32),
+        _ => pitch.to_string(),
+    }
+}
+
+/// パート内の音符ごとに、`-` でタイを書いたかどうか（和音の構成音の書き方の判定に使う）
+fn dash_tied_notes(part: &Part) -> HashMap<NoteRef, bool> {
+    fn collect(measure: usize, elements: &[ScoreElement], out: &mut HashMap<NoteRef, bool>) {
+        for elem in elements {
+            let events: &[Event] = match elem {
+                ScoreElement::Event(ev) => std::slice::from_ref(ev),
+                ScoreElement::Chord(chord) => &chord.events,
+                ScoreElement::Subdivision(sub) => {
+                    collect(measure, &sub.elements, out);
+                    &[]
+                }
+                ScoreElement::Tie(_) => &[],
+            };
+            for ev in events {
+                if let Some(id) = ev.id {
+                    out.insert(NoteRef { measure, id }, ev.tie);
+                }
+            }
+        }
+    }
+    let mut out = HashMap::new();
+    for measure in &part.measures {
+        for voice in &measure.voices {
+            for beat in &voice.beats {
+                collect(measure.number, &beat.elements, &mut out);
+            }
+        }
+    }
+    out
+}
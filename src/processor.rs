@@ -1,4 +1,4 @@
-use crate::data::{Score, ScoreElement};
+use crate::data::{Score, ScoreElement, EventType, Measure, MeasureLength};
 use num_rational::Ratio;
 
 /// ScoreElementのDurationを再帰的に割り当てる（分数で計算）
@@ -13,8 +13,12 @@ fn assign_element_durations(elements: &mut [ScoreElement], duration: Ratio<i32>)
             }
             ScoreElement::Subdivision(sub) => {
                 let div = sub.base_division as i32;
-                let sub_duration = duration / Ratio::from_integer(div);
-                assign_element_durations(&mut sub.elements, sub_duration);
+                let unit_duration = duration / Ratio::from_integer(div);
+                // 各要素に重み分の単位長を割り当てる
+                for (child, &weight) in sub.elements.iter_mut().zip(&sub.weights) {
+                    let child_duration = unit_duration * Ratio::from_integer(weight as i32);
+                    assign_element_durations(std::slice::from_mut(child), child_duration);
+                }
             }
             ScoreElement::Chord(chord) => {
                 for ev in &mut chord.events {
@@ -25,21 +29,52 @@ fn assign_element_durations(elements: &mut [ScoreElement], duration: Ratio<i32>)
     }
 }
 
+/// 休符だけからなる要素列かどうか
+fn is_rest_only(elements: &[ScoreElement]) -> bool {
+    elements.iter().all(|elem| match elem {
+        ScoreElement::Event(ev) => ev.event_type == EventType::Rest,
+        ScoreElement::Subdivision(sub) => is_rest_only(&sub.elements),
+        ScoreElement::Tie(_) | ScoreElement::Chord(_) => false,
+    })
+}
+
+/// 弱起の小節から、全ての声部で休符だけになっている先頭の拍を取り除く（最低1拍は残す）
+fn trim_pickup_rests(measure: &mut Measure) {
+    let beat_count = measure.voices.iter().map(|v| v.beats.len()).min().unwrap_or(0);
+    let leading_rests = measure.voices.iter()
+        .map(|v| v.beats.iter().take_while(|b| is_rest_only(&b.elements)).count())
+        .min()
+        .unwrap_or(0)
+        .min(beat_count.saturating_sub(1));
+    for voice in &mut measure.voices {
+        voice.beats.drain(..leading_rests);
+    }
+}
+
 /// スコア全体を処理する関数（例: タイや持続時間の計算など）
 pub fn process_score(mut score: Score) -> Score {
     for part in &mut score.parts {
         for measure in &mut part.measures {
             // meter: (分子, 分母) 例: (4, 4)
-            let (beats, beat_type) = measure.meter;
-            let beat_length = 4.0 / beat_type as f32;
-            measure.duration = beats as f32 * beat_length;
-            measure.unit_duration = beat_length;
-            // 各拍にunit_durationを割り当てる
-            for beat in &mut measure.beats {
-                beat.duration = beat_length;
-                // Ratio::from_float(beat_length)はRatio<BigInt>を返すため、i32で分数を作る
-                let beat_ratio = Ratio::new((beat_length * 10000.0) as i32, 10000);
-                assign_element_durations(&mut beat.elements, beat_ratio);
+            let (numerator, beat_type) = measure.meter;
+            if measure.length == MeasureLength::Pickup {
+                trim_pickup_rests(measure);
+            }
+            // 弱起・不完全小節は実際に書かれた拍の長さの合計を小節の長さとする
+            let units = match measure.length {
+                MeasureLength::Full => numerator,
+                _ => measure.voices.first().map_or(numerator, |v| v.beats.iter().map(|b| b.units).sum()),
+            };
+            let unit_length = 4.0 / beat_type as f32;
+            measure.duration = units as f32 * unit_length;
+            measure.unit_duration = unit_length;
+            // 各声部の各拍に、拍が占める分母単位数分の長さを割り当てる（6/8 を2拍で書けば付点4分）
+            for voice in &mut measure.voices {
+                for beat in &mut voice.beats {
+                    beat.duration = beat.units as f32 * unit_length;
+                    let beat_ratio = Ratio::new(4 * beat.units as i32, beat_type as i32);
+                    assign_element_durations(&mut beat.elements, beat_ratio);
+                }
             }
         }
     }
@@ -7,14 +7,14 @@ use regex::Regex;
 /// - pvsc_content: parsed_vsc.pvscの内容
 /// - output_path: 出力SVGファイル名
 pub fn render_svg(score_def: &ScoreDef, pvsc_content: &str, output_path: &str) -> anyhow::Result<()> {
-    use svg::node::element::{Group, Line, Circle};
+    use svg::node::element::{Group, Line, Circle, Text};
     use svg::Document;
     use num_integer::gcd;
 
     // 最初のパート・最初の小節のnotesを抽出
-    let part = score_def.score.parts.get(0)
+    let part = score_def.score.parts.first()
         .ok_or_else(|| anyhow::anyhow!("パートが見つかりません"))?;
-    let measure_num = 1;
+    let measure_num = part.notes.iter().map(|n| n.measure).min().unwrap_or(1);
     let notes: Vec<_> = part.notes.iter().filter(|n| n.measure == measure_num).collect();
 
     // pvscからduration情報を取得
@@ -23,7 +23,7 @@ pub fn render_svg(score_def: &ScoreDef, pvsc_content: &str, output_path: &str) -
 
     // (measure, id) -> (numer, denom)
     let mut duration_map = HashMap::new();
-    let mut current_measure = 0;
+    let mut current_measure;
     for cap in re_measure.captures_iter(pvsc_content) {
         current_measure = cap[1].parse::<usize>().unwrap_or(0);
         // イベントをこのmeasure内で探す
@@ -46,6 +46,14 @@ pub fn render_svg(score_def: &ScoreDef, pvsc_content: &str, output_path: &str) -
     let staff_spacing = 12;
     let staff_lines = 5;
     let note_radius = 7;
+    // 歌詞は譜表の下に番ごとに1行ずつ並べる
+    let lyric_top = staff_top + staff_spacing * (staff_lines - 1) + 28;
+    let lyric_spacing = 16;
+    let verse_count = notes.iter()
+        .flat_map(|n| n.lyrics.iter().map(|l| l.verse))
+        .max()
+        .unwrap_or(0);
+    let height = height.max(lyric_top + lyric_spacing * verse_count as i32);
 
     // 5線譜を描画
     let mut group = Group::new();
@@ -85,7 +93,7 @@ pub fn render_svg(score_def: &ScoreDef, pvsc_content: &str, output_path: &str) -
             if let Some(&(numer, denom)) = duration_map.get(&(note.measure, next_id)) {
                 // 分数加算: a/b + c/d = (a*d + c*b)/(b*d)
                 total_numer = total_numer * denom + numer * total_denom;
-                total_denom = total_denom * denom;
+                total_denom *= denom;
                 // 約分
                 let d = gcd(total_numer, total_denom);
                 if d > 1 {
@@ -121,6 +129,32 @@ pub fn render_svg(score_def: &ScoreDef, pvsc_content: &str, output_path: &str) -
             circle = circle.set("fill", "black");
         }
         group = group.add(circle);
+
+        // 歌詞（音節の後のハイフン、延ばす線も描く）
+        for lyric in &note.lyrics {
+            let y = (lyric_top + lyric_spacing * (lyric.verse as i32 - 1)) as f32;
+            group = group.add(Text::new(lyric.text.clone())
+                .set("x", cx)
+                .set("y", y)
+                .set("text-anchor", "middle")
+                .set("font-size", 12));
+            if lyric.syllabic == "begin" || lyric.syllabic == "middle" {
+                group = group.add(Text::new("-")
+                    .set("x", cx + note_spacing / 2.0)
+                    .set("y", y)
+                    .set("text-anchor", "middle")
+                    .set("font-size", 12));
+            }
+            if lyric.extender {
+                group = group.add(Line::new()
+                    .set("x1", cx + note_radius as f32 * 2.0)
+                    .set("y1", y)
+                    .set("x2", cx + note_spacing * 0.9)
+                    .set("y2", y)
+                    .set("stroke", "black")
+                    .set("stroke-width", 1));
+            }
+        }
     }
 
     let document = Document::new()
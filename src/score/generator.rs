@@ -1,49 +1,161 @@
 // Score→score_def.yaml変換ロジック
-use crate::data::Score;
+use crate::data::{Part, ScoreElement, Score, INSTRUMENTS};
 use crate::score::score_def_data::*;
-use crate::score::grouping::group_measure_elements;
+use crate::score::grouping::{group_measure_elements, inline_dynamics, measure_rest_entry, TieLinks};
 use serde_yaml;
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 /// Score構造体からScoreDef(YAML用)を生成しYAML文字列として返す
 pub fn generate_score_def_yaml_from_score(score: &Score) -> Result<String> {
-    // デフォルト値
-    let tempo = vec![TempoSetting {
-        measure: 1,
-        position: 1.0,
-        bpm: 120,
-    }];
-    let key_signature = vec![KeySignatureSetting {
-        measure: 1,
-        position: 1.0,
-        key: "C_Major".to_string(),
-    }];
+    // 最初の小節（弱起があれば0小節目）
+    let first_measure = score.parts.iter()
+        .flat_map(|p| p.measures.iter().map(|m| m.number))
+        .min()
+        .unwrap_or(1);
+    // VSCの `tempo=96`・`key=Eb`。スコア全体の指定なので、パートをまたいで同じ位置の指定は同じでなければならない
+    let mut tempo: Vec<TempoSetting> = Vec::new();
+    let mut key_signature: Vec<KeySignatureSetting> = Vec::new();
+    for measure in score.parts.iter().flat_map(|p| p.measures.iter()) {
+        for change in &measure.marks.tempo {
+            let position = *change.position.numer() as f32 / *change.position.denom() as f32;
+            match tempo.iter().find(|t| t.measure == measure.number && t.position == position) {
+                Some(t) if t.bpm != change.bpm => bail!(
+                    "Tempo at measure {} position {} differs between parts ({} and {})",
+                    measure.number, position, t.bpm, change.bpm
+                ),
+                Some(_) => {}
+                None => tempo.push(TempoSetting { measure: measure.number, position, bpm: change.bpm }),
+            }
+        }
+        for change in &measure.marks.key {
+            let position = *change.position.numer() as f32 / *change.position.denom() as f32;
+            match key_signature.iter().find(|k| k.measure == measure.number && k.position == position) {
+                Some(k) if k.key != change.key => bail!(
+                    "Key at measure {} position {} differs between parts ({} and {})",
+                    measure.number, position, k.key, change.key
+                ),
+                Some(_) => {}
+                None => key_signature.push(KeySignatureSetting { measure: measure.number, position, key: change.key.clone() }),
+            }
+        }
+    }
+    // 曲頭に指定がなければデフォルト値
+    if !tempo.iter().any(|t| t.measure == first_measure && t.position == 1.0) {
+        tempo.insert(0, TempoSetting { measure: first_measure, position: 1.0, bpm: 120 });
+    }
+    if !key_signature.iter().any(|k| k.measure == first_measure && k.position == 1.0) {
+        key_signature.insert(0, KeySignatureSetting { measure: first_measure, position: 1.0, key: "C_Major".to_string() });
+    }
+    tempo.sort_by(|a, b| (a.measure, a.position).partial_cmp(&(b.measure, b.position)).unwrap());
+    key_signature.sort_by(|a, b| (a.measure, a.position).partial_cmp(&(b.measure, b.position)).unwrap());
 
     let mut parts = Vec::new();
     for part in &score.parts {
+        // 譜表数から一段譜・大譜表・多段譜を決める
+        let (staff_type, staff_count) = match part.staff_count {
+            1 => (Some("single".to_string()), None),
+            2 => (Some("grand".to_string()), None),
+            n => (None, Some(n as u8)),
+        };
+        // 名前で書いた打楽器の音だけのパートは打楽器譜にする
+        let percussion = is_percussion(part);
+        // 音部記号と移調はヘッダーの指定、組み込みの楽器の既定値、譜表数からの既定値の順に決める
+        let instrument = part.instrument.as_deref()
+            .and_then(|name| INSTRUMENTS.iter().find(|(n, _, _)| *n == name));
+        let clef: Vec<String> = if !part.clefs.is_empty() {
+            part.clefs.clone()
+        } else if let Some((_, clefs, _)) = instrument.filter(|(_, clefs, _)| clefs.len() == part.staff_count) {
+            clefs.iter().map(|c| c.to_string()).collect()
+        } else if percussion {
+            vec!["Percussion".to_string(); part.staff_count]
+        } else if part.staff_count == 2 {
+            vec!["Treble".to_string(), "Bass".to_string()]
+        } else {
+            vec!["Treble".to_string(); part.staff_count]
+        };
+        let transposition = part.transposition
+            .or(instrument.map(|(_, _, t)| *t))
+            .filter(|&t| t != 0)
+            .map(|t| TranspositionSetting { measure: first_measure, position: 1.0, transposition_intaval: t })
+            .into_iter()
+            .collect();
         let staves = vec![StavesSetting {
-            measure: 1,
+            measure: first_measure,
             position: 1.0,
-            r#type: "single".to_string(),
-            lines: vec![5],
-        }];
-        let dynamics = vec![DynamicsSetting {
-            measure: 1,
-            position: 1.0,
-            level: "P".to_string(),
+            r#type: staff_type,
+            staff_count,
+            clef,
+            chromatic_assignment: percussion,
+            lines: vec![5; part.staff_count],
         }];
+        // VSCの強弱記号（`G5!ff`）。声部・譜表をまたいで同じ位置の同じ記号は1つにまとめる
+        let mut dynamics: Vec<DynamicsSetting> = Vec::new();
+        for measure in &part.measures {
+            for voice in &measure.voices {
+                let elements: Vec<_> = voice.beats.iter().flat_map(|b| b.elements.iter().cloned()).collect();
+                for (offset, level) in inline_dynamics(&elements) {
+                    let position = 1.0 + (*offset.numer() as f32 / *offset.denom() as f32) / measure.unit_duration;
+                    let duplicate = dynamics.iter()
+                        .any(|d| d.measure == measure.number && d.position == position && d.level == level);
+                    if !duplicate {
+                        dynamics.push(DynamicsSetting { measure: measure.number, position, level });
+                    }
+                }
+            }
+        }
+        // 曲頭に強弱記号がなければデフォルト値
+        if !dynamics.iter().any(|d| d.measure == first_measure && d.position == 1.0) {
+            dynamics.insert(0, DynamicsSetting {
+                measure: first_measure,
+                position: 1.0,
+                level: "P".to_string(),
+            });
+        }
+        dynamics.sort_by(|a, b| (a.measure, a.position).partial_cmp(&(b.measure, b.position)).unwrap());
+        // タイ・ピッチスライドの接続（小節をまたぐものも含む）
+        let mut links = TieLinks::default();
+        for measure in &part.measures {
+            for voice in &measure.voices {
+                for beat in &voice.beats {
+                    links.add_elements(measure.number, &beat.elements);
+                }
+            }
+        }
         let mut notes = Vec::new();
         for measure in &part.measures {
-            // 各小節の全BeatのScoreElementをフラット化
-            let mut elements = Vec::new();
-            for beat in &measure.beats {
-                elements.extend(beat.elements.iter().cloned());
+            for voice in &measure.voices {
+                // 声部ごとに全BeatのScoreElementをフラット化
+                let mut elements = Vec::new();
+                for beat in &voice.beats {
+                    elements.extend(beat.elements.iter().cloned());
+                }
+                let mut note_entries = match &voice.measure_rest {
+                    Some(rest) => measure_rest_entry(measure.number, voice.staff, voice.number, &elements, rest),
+                    None => group_measure_elements(measure.number, voice.staff, voice.number, &elements, &links),
+                };
+                notes.append(&mut note_entries);
+            }
+        }
+        // 歌詞は音符・和音のidで対応付け、タイで分割された最初の音価に付ける
+        let mut lyrics: Vec<_> = part.lyrics.iter().collect();
+        lyrics.sort_by_key(|l| l.verse);
+        for lyric in lyrics {
+            let entry = notes.iter_mut().find(|n| {
+                n.measure == lyric.note.measure && n.staff == lyric.staff && n.voice == lyric.voice && n.id == lyric.note.id as usize
+            });
+            if let Some(entry) = entry {
+                entry.lyrics.push(LyricSetting {
+                    verse: lyric.verse,
+                    text: lyric.text.clone(),
+                    syllabic: lyric.syllabic.name().to_string(),
+                    extender: lyric.extender,
+                });
             }
-            let mut note_entries = group_measure_elements(measure.number, &elements);
-            notes.append(&mut note_entries);
         }
         parts.push(PartSetting {
             name: part.name.clone(),
+            instrument: part.instrument.clone(),
+            transposition,
             staves,
             dynamics,
             notes,
@@ -88,3 +200,126 @@ pub fn generate_score_def_yaml_from_score(score: &Score) -> Result<String> {
     }
     Ok(lines.join("\n"))
 }
+
+/// パートの音符がすべて名前で書いた打楽器の音（`kick`, `hh.open`）かどうか
+fn is_percussion(part: &Part) -> bool {
+    fn visit(elements: &[ScoreElement], pitched: &mut bool, unpitched: &mut bool) {
+        for elem in elements {
+            match elem {
+                ScoreElement::Event(ev) if ev.pitch.is_some() => {
+                    *unpitched |= ev.unpitched.is_some();
+                    *pitched |= ev.unpitched.is_none();
+                }
+                ScoreElement::Chord(chord) => {
+                    for ev in &chord.events {
+                        *unpitched |= ev.unpitched.is_some();
+                        *pitched |= ev.unpitched.is_none();
+                    }
+                }
+                ScoreElement::Subdivision(sub) => visit(&sub.elements, pitched, unpitched),
+                _ => {}
+            }
+        }
+    }
+    let (mut pitched, mut unpitched) = (false, false);
+    for voice in part.measures.iter().flat_map(|m| m.voices.iter()) {
+        for beat in &voice.beats {
+            visit(&beat.elements, &mut pitched, &mut unpitched);
+        }
+    }
+    unpitched && !pitched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_score;
+    use crate::processor::process_score;
+
+    /// VSCから生成した score_def.yaml を読み直したもの
+    fn score_def(src: &str) -> ScoreDef {
+        let score = process_score(parse_score(src).expect("parse"));
+        let yaml = generate_score_def_yaml_from_score(&score).expect("generate");
+        serde_yaml::from_str(&yaml).expect("yaml")
+    }
+
+    fn dynamics(part: &PartSetting) -> Vec<(usize, f32, &str)> {
+        part.dynamics.iter().map(|d| (d.measure, d.position, d.level.as_str())).collect()
+    }
+
+    #[test]
+    fn inline_dynamics_are_placed_at_their_position() {
+        let def = score_def(concat!(
+            "#[Part(A)]\n",
+            "1: 4/4 [C5, [D5!f, E5!ff], F5, G5]\n",
+            "2: [C5!pp, D5, E5, F5]\n",
+            "#[Voice(2)]\n",
+            "1: [C4, [D4!f, E4], F4, r]\n",
+            "2: [C4!pp, D4, E4, F4]\n",
+        ));
+        // 既定の P を曲頭に置き、声部をまたいで同じ位置の同じ記号は1つにする
+        assert_eq!(dynamics(&def.score.parts[0]), vec![(1, 1.0, "P"), (1, 2.0, "F"), (1, 2.5, "FF"), (2, 1.0, "PP")]);
+    }
+
+    #[test]
+    fn default_dynamic_is_omitted_when_the_first_note_has_one() {
+        let def = score_def("#[Part(A)]\n1: 4/4 [{C5, E5!mf}, D5, E5, F5]\n");
+        assert_eq!(dynamics(&def.score.parts[0]), vec![(1, 1.0, "MF")]);
+
+        // 弱起があれば0小節目の頭が曲頭になる
+        let def = score_def("#[Part(A)]\n0: 4/4 pickup [r, r, r, G4]\n1: [C5!f, D5, E5, F5]\n");
+        assert_eq!(dynamics(&def.score.parts[0]), vec![(0, 1.0, "P"), (1, 1.0, "F")]);
+    }
+
+    #[test]
+    fn tempo_and_key_marks_are_placed_mid_measure() {
+        let def = score_def(concat!(
+            "#[Part(A)]\n",
+            "1: 4/4 [C5, D5, E5, F5]\n",
+            "2: 3/4 key=Eb tempo=96 [C5, D5, E5] tempo=72@3 key=G@2.5\n",
+            "#[Part(B)]\n",
+            "1: 4/4 [C4, D4, E4, F4]\n",
+            "2: 3/4 tempo=96 [C4, D4, E4]\n",
+        ));
+        let tempo: Vec<(usize, f32, u32)> = def.score.tempo.iter().map(|t| (t.measure, t.position, t.bpm)).collect();
+        assert_eq!(tempo, vec![(1, 1.0, 120), (2, 1.0, 96), (2, 3.0, 72)]);
+        let keys: Vec<(usize, f32, &str)> = def.score.key_signature.iter().map(|k| (k.measure, k.position, k.key.as_str())).collect();
+        assert_eq!(keys, vec![(1, 1.0, "C_Major"), (2, 1.0, "E_Flat_Major"), (2, 2.5, "G_Major")]);
+
+        // 同じ位置のテンポがパートごとに異なればエラー
+        let score = process_score(parse_score(concat!(
+            "#[Part(A)]\n1: 4/4 [C5, D5, E5, F5] tempo=72@3\n",
+            "#[Part(B)]\n1: 4/4 [C4, D4, E4, F4] tempo=80@3\n",
+        )).expect("parse"));
+        let error = generate_score_def_yaml_from_score(&score).expect_err("conflict").to_string();
+        assert_eq!(error, "Tempo at measure 1 position 3 differs between parts (72 and 80)");
+        assert!(parse_score("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5] tempo=72@5\n").is_err());
+    }
+
+    #[test]
+    fn parts_written_only_with_drum_names_become_percussion() {
+        let def = score_def(concat!(
+            "#[Drum(cowbell2, key=56, display=E5, notehead=triangle)]\n",
+            "#[Part(Drums)]\n1: 4/4 [{kick, hh}, [snare, snare], r, cowbell2]\n",
+            "#[Part(Mixed)]\n1: 4/4 [kick, C5, r, r]\n",
+            "#[Part(Marked, clef=treble)]\n1: 4/4 [kick, snare, kick, snare]\n",
+        ));
+        let staves: Vec<(&str, Vec<String>, bool)> = def.score.parts.iter()
+            .map(|p| (p.name.as_str(), p.staves[0].clef.clone(), p.staves[0].chromatic_assignment))
+            .collect();
+        assert_eq!(staves, vec![
+            ("Drums", vec!["Percussion".to_string()], true),
+            ("Mixed", vec!["Treble".to_string()], false),
+            ("Marked", vec!["Treble".to_string()], true),
+        ]);
+
+        // 打楽器の音は名前・MIDIキー・譜表上の位置・符頭を持つ
+        let unpitched: Vec<(String, u8, String, String)> = def.score.parts[0].notes.iter()
+            .flat_map(|n| n.attributes.iter())
+            .filter_map(|a| a.unpitched.as_ref())
+            .map(|u| (u.name.clone(), u.midi_key, u.display.clone(), u.notehead.clone()))
+            .collect();
+        assert_eq!(unpitched.last(), Some(&("cowbell2".to_string(), 56, "E5".to_string(), "triangle".to_string())));
+        assert_eq!(unpitched[0].0, "kick");
+    }
+}
@@ -1,12 +1,72 @@
 // 音価グルーピング・タイ分解ロジック
-use crate::data::{ScoreElement, Event, Tie, Chord, EventType};
-use crate::score::score_def_data::{NoteEntry, NoteAttributes};
+use crate::data::{ScoreElement, Event, Tie, Chord, EventType, NoteRef, GraceKind, MeasureRest, Unpitched};
+use crate::score::score_def_data::{NoteEntry, NoteAttributes, PitchSlide, MeasureRestSetting, UnpitchedSetting};
 use num_rational::Ratio;
+use std::collections::{HashMap, HashSet};
 
-/// 小節ごとのScoreElement列からグルーピング後のNoteEntry列を生成
+/// パート全体のタイの接続情報（小節をまたぐものも含む）
+#[derive(Default)]
+pub struct TieLinks {
+    /// 後続の音からタイで接続されている音（`tied_from` の参照先）
+    pub continued: HashSet<NoteRef>,
+    /// 音高を変えるタイの接続元 → スライド先の音
+    pub slides: HashMap<NoteRef, NoteRef>,
+    /// 名前で書いた打楽器の音。タイ `t` は接続元の音を引き継ぐ
+    pub unpitched: HashMap<NoteRef, Unpitched>,
+}
+
+impl TieLinks {
+    /// 小節内の要素列に含まれるタイの接続を登録する
+    pub fn add_elements(&mut self, measure: usize, elements: &[ScoreElement]) {
+        for elem in elements {
+            match elem {
+                ScoreElement::Event(ev) => {
+                    self.add(measure, ev.id, ev.tied_from, ev.slide);
+                    self.add_unpitched(measure, ev.id, ev.unpitched.clone());
+                }
+                ScoreElement::Tie(tie) => {
+                    self.add(measure, tie.id, tie.tied_from, tie.slide);
+                    let origin = tie.tied_from.filter(|_| !tie.slide).and_then(|r| self.unpitched.get(&r).cloned());
+                    self.add_unpitched(measure, tie.id, origin);
+                }
+                ScoreElement::Chord(chord) => {
+                    for ev in &chord.events {
+                        self.add(measure, ev.id, ev.tied_from, ev.slide);
+                        self.add_unpitched(measure, ev.id, ev.unpitched.clone());
+                    }
+                }
+                ScoreElement::Subdivision(sub) => self.add_elements(measure, &sub.elements),
+            }
+        }
+    }
+
+    fn add_unpitched(&mut self, measure: usize, id: Option<u64>, unpitched: Option<Unpitched>) {
+        if let (Some(id), Some(unpitched)) = (id, unpitched) {
+            self.unpitched.insert(NoteRef { measure, id }, unpitched);
+        }
+    }
+
+    fn add(&mut self, measure: usize, id: Option<u64>, tied_from: Option<NoteRef>, slide: bool) {
+        let Some(origin) = tied_from else { return };
+        match id {
+            Some(id) if slide => {
+                self.slides.insert(origin, NoteRef { measure, id });
+            }
+            _ => {
+                self.continued.insert(origin);
+            }
+        }
+    }
+}
+
+/// 小節・声部ごとのScoreElement列からグルーピング後のNoteEntry列を生成
+/// `links` はパート全体のタイ・ピッチスライドの接続情報
 pub fn group_measure_elements(
     measure_num: usize,
+    staff: usize,
+    voice: usize,
     elements: &[ScoreElement],
+    links: &TieLinks,
 ) -> Vec<NoteEntry> {
     // 1. Subdivisionを再帰的に展開し、フラットなイベント列にする
     let mut flat_events = Vec::new();
@@ -29,8 +89,9 @@ pub fn group_measure_elements(
                         current_event_type = ev.event_type.clone();
                         representative_id = ev.id.map(|id| id as usize);
                     }
-                    if first || (ev.tie && !matches!(ev.event_type, EventType::Rest)) {
-                        total_duration += ev.duration.clone();
+                    // 後続の音符は新しい音（`C5-, C5` のタイはtieフラグで結ぶ）
+                    if first {
+                        total_duration += ev.duration;
                         first = false;
                         j += 1;
                     } else {
@@ -38,20 +99,32 @@ pub fn group_measure_elements(
                     }
                 }
                 FlatElem::Tie(tie) => {
-                    total_duration += tie.duration.clone();
+                    // 音高を変えるタイは新しい音として扱う（前のランからピッチスライドで結ぶ）
+                    if !first && tie.slide {
+                        break;
+                    }
+                    // 前の小節から続くタイ、またはピッチスライド先で始まるラン
+                    if first {
+                        current_event_type = EventType::Note;
+                        representative_id = tie.id.map(|id| id as usize);
+                        first = false;
+                    }
+                    total_duration += tie.duration;
                     j += 1;
                 }
                 FlatElem::Chord(chord) => {
                     // 和音は単独で扱うが、直後にTie(t)があれば和音全体にタイをかける
                     if first {
-                        total_duration += chord.events.get(0).map(|e| e.duration.clone()).unwrap_or(Ratio::new(0,1));
+                        total_duration += chord.events.first().map(|e| e.duration).unwrap_or(Ratio::new(0,1));
                         current_event_type = EventType::Note;
                         representative_id = chord.id.map(|id| id as usize);
                         // 直後にTieがあればdurationを加算
                         if j+1 < flat_events.len() {
                             if let FlatElem::Tie(tie) = &flat_events[j+1] {
-                                total_duration += tie.duration.clone();
-                                j += 1; // Tieも消費
+                                if !tie.slide {
+                                    total_duration += tie.duration;
+                                    j += 1; // Tieも消費
+                                }
                             }
                         }
                         j += 1;
@@ -60,30 +133,79 @@ pub fn group_measure_elements(
                 }
             }
         }
+        // 主音の前に前打音を置く（拍を消費しないため、音価は記譜上の8分・16分音符）
+        if let Some(FlatElem::Event(ev)) = flat_events.get(i) {
+            notes.extend(grace_note_entries(measure_num, staff, voice, ev));
+        }
+        // 入力された臨時記号（和音は構成音ごと）。タイで分割された2つ目以降には付けない
+        let mut accidentals = run_accidentals(flat_events.get(i));
+        // 奏法記号も同様に最初の音価にだけ付ける
+        let mut articulations = run_articulations(flat_events.get(i));
+        // 打楽器の音はタイで分割されたすべての音価に付ける
+        let unpitched = run_unpitched(flat_events.get(i), measure_num, links);
+        // ランの最後から次の音へタイ・ピッチスライドが続くか（和音は構成音ごと）
+        let link = |flag: bool, ids: &[Option<u64>]| -> (bool, Option<NoteRef>) {
+            let refs: Vec<NoteRef> = ids.iter().flatten().map(|&id| NoteRef { measure: measure_num, id }).collect();
+            let slide = refs.iter().find_map(|r| links.slides.get(r).copied());
+            let tie = (flag || refs.iter().any(|r| links.continued.contains(r))) && slide.is_none();
+            (tie, slide)
+        };
+        let onward: Vec<(bool, Option<NoteRef>)> = match (flat_events.get(i), j.checked_sub(1).and_then(|k| flat_events.get(k))) {
+            // 和音の直後のtで和音全体を伸ばした場合
+            (Some(FlatElem::Chord(chord)), Some(FlatElem::Tie(tie))) => {
+                chord.events.iter().map(|ev| link(false, &[ev.id, tie.id])).collect()
+            }
+            (Some(FlatElem::Chord(chord)), _) => {
+                chord.events.iter().map(|ev| link(ev.tie, &[ev.id, chord.id])).collect()
+            }
+            (_, Some(FlatElem::Event(ev))) => vec![link(ev.tie, &[ev.id])],
+            (_, Some(FlatElem::Tie(tie))) => vec![link(false, &[tie.id])],
+            _ => vec![(false, None)],
+        };
         // 3. 記譜値集合Dによる貪欲分解
-        let mut remain = total_duration.clone();
+        let mut remain = total_duration;
         let durations = get_note_durations();
-        let mut first_note = true;
         while remain > Ratio::new(0,1) {
             let mut found = false;
             for d in &durations {
                 if *d <= remain {
+                    // 分割された途中の音価は次の音価へ必ずタイで結ぶ
+                    let is_last_piece = *d == remain;
                     notes.push(NoteEntry {
                         measure: measure_num,
+                        staff,
+                        voice,
                         id: representative_id.unwrap_or(0), // pvscのidを使う
-                        attributes: vec![NoteAttributes {
+                        attributes: accidentals.iter().enumerate().map(|(k, accidental)| NoteAttributes {
                             r#type: if current_event_type == EventType::Rest { "rest".to_string() } else { "note".to_string() },
-                            accidental: "None".to_string(),
+                            accidental: accidental.clone(),
+                            articulations: articulations.get(k).cloned().unwrap_or_default(),
                             duration: format!("{}/{}", d.numer(), d.denom()),
-                        }],
+                            tie: current_event_type != EventType::Rest
+                                && (!is_last_piece || onward.get(k).is_some_and(|o| o.0)),
+                            pitch_slide: onward.get(k)
+                                .and_then(|o| o.1)
+                                .filter(|_| is_last_piece && current_event_type != EventType::Rest)
+                                .map(|end| PitchSlide {
+                                    r#type: "glissando".to_string(),
+                                    slide_end_measure: end.measure,
+                                    slide_end_id: end.id as usize,
+                                }),
+                            grace_for: None,
+                            measure_rest: None,
+                            unpitched: unpitched.get(k).cloned().flatten(),
+                        }).collect(),
                         source_ids: if let Some(FlatElem::Chord(chord)) = flat_events.get(i) {
                             Some(chord.events.iter().filter_map(|e| e.id.map(|id| id as usize)).collect())
                         } else {
                             None
                         },
+                        lyrics: Vec::new(),
                     });
                     // もし分割音価が複数に分かれる場合、idは最初のidを使い続ける（必要ならsource_idsリスト化も検討）
                     remain -= *d;
+                    accidentals.iter_mut().for_each(|a| *a = "None".to_string());
+                    articulations.iter_mut().for_each(Vec::clear);
                     found = true;
                     break;
                 }
@@ -102,6 +224,128 @@ pub fn group_measure_elements(
     notes
 }
 
+/// 小節・声部内の強弱記号（`G5!ff`）を、小節頭からのDuration（4分音符=1）と DynamicsLevel名の組で返す
+/// 和音は構成音のいずれかに書かれたものを和音の位置に置く
+pub fn inline_dynamics(elements: &[ScoreElement]) -> Vec<(Ratio<i32>, String)> {
+    let mut flat_events = Vec::new();
+    flatten_elements(elements, &mut flat_events);
+    let mut offset = Ratio::new(0, 1);
+    let mut dynamics = Vec::new();
+    for elem in flat_events {
+        let (duration, events): (Ratio<i32>, &[Event]) = match elem {
+            FlatElem::Event(ev) => (ev.duration, std::slice::from_ref(ev)),
+            FlatElem::Tie(tie) => (tie.duration, &[]),
+            FlatElem::Chord(chord) => (chord.events.first().map_or(Ratio::new(0, 1), |e| e.duration), &chord.events),
+        };
+        if let Some(level) = events.iter().find_map(|ev| ev.dynamic.clone()) {
+            dynamics.push((offset, level));
+        }
+        offset += duration;
+    }
+    dynamics
+}
+
+/// 小節全体の休符（`R`）のエントリ。音価に分解せず、小節の長さの休符1つにする
+pub fn measure_rest_entry(
+    measure_num: usize,
+    staff: usize,
+    voice: usize,
+    elements: &[ScoreElement],
+    rest: &MeasureRest,
+) -> Vec<NoteEntry> {
+    let Some(ScoreElement::Event(ev)) = elements.first() else {
+        return Vec::new();
+    };
+    vec![NoteEntry {
+        measure: measure_num,
+        staff,
+        voice,
+        id: ev.id.unwrap_or(0) as usize,
+        attributes: vec![NoteAttributes {
+            r#type: "rest".to_string(),
+            accidental: "None".to_string(),
+            articulations: Vec::new(),
+            duration: format!("{}/{}", ev.duration.numer(), ev.duration.denom()),
+            tie: false,
+            pitch_slide: None,
+            grace_for: None,
+            measure_rest: Some(MeasureRestSetting { first_measure: rest.first, measure_count: rest.count }),
+            unpitched: None,
+        }],
+        source_ids: None,
+        lyrics: Vec::new(),
+    }]
+}
+
+/// 主音に付く前打音のエントリ。単独なら8分音符、複数なら16分音符で書く
+fn grace_note_entries(measure_num: usize, staff: usize, voice: usize, ev: &Event) -> Vec<NoteEntry> {
+    let duration = if ev.grace_notes.len() == 1 { "1/2" } else { "1/4" };
+    ev.grace_notes.iter().map(|grace| NoteEntry {
+        measure: measure_num,
+        staff,
+        voice,
+        id: grace.id.unwrap_or(0) as usize,
+        attributes: vec![NoteAttributes {
+            r#type: match grace.kind {
+                GraceKind::Acciaccatura => "acciaccatura".to_string(),
+                GraceKind::Appoggiatura => "appoggiatura".to_string(),
+            },
+            accidental: grace.pitch.accidental_name(),
+            articulations: Vec::new(),
+            duration: duration.to_string(),
+            tie: false,
+            pitch_slide: None,
+            grace_for: ev.id.map(|id| id as usize),
+            measure_rest: None,
+            unpitched: None,
+        }],
+        source_ids: None,
+        lyrics: Vec::new(),
+    }).collect()
+}
+
+/// ランの先頭要素の臨時記号名。和音の場合は構成音ごと（source_idsと同じ順）
+/// タイで前の音から続く音には臨時記号を付けない
+fn run_accidentals(first: Option<&FlatElem>) -> Vec<String> {
+    let event_accidental = |ev: &Event| match (&ev.pitch, ev.tied_from) {
+        (Some(p), None) => p.accidental_name(),
+        (Some(p), Some(_)) if ev.slide => p.accidental_name(),
+        _ => "None".to_string(),
+    };
+    match first {
+        Some(FlatElem::Event(ev)) => vec![event_accidental(ev)],
+        Some(FlatElem::Tie(tie)) if tie.slide => vec![tie.pitch.as_ref().map_or("None".to_string(), |p| p.accidental_name())],
+        Some(FlatElem::Chord(chord)) if !chord.events.is_empty() => chord.events.iter().map(event_accidental).collect(),
+        _ => vec!["None".to_string()],
+    }
+}
+
+/// ランの先頭要素の奏法記号名。和音の場合は構成音ごと（source_idsと同じ順）
+fn run_articulations(first: Option<&FlatElem>) -> Vec<Vec<String>> {
+    let names = |ev: &Event| ev.articulations.iter().map(|a| a.name().to_string()).collect();
+    match first {
+        Some(FlatElem::Event(ev)) => vec![names(ev)],
+        Some(FlatElem::Chord(chord)) if !chord.events.is_empty() => chord.events.iter().map(names).collect(),
+        _ => vec![Vec::new()],
+    }
+}
+
+/// ランの先頭要素の打楽器の音。和音の場合は構成音ごと（source_idsと同じ順）
+fn run_unpitched(first: Option<&FlatElem>, measure: usize, links: &TieLinks) -> Vec<Option<UnpitchedSetting>> {
+    let setting = |u: &Unpitched| UnpitchedSetting {
+        name: u.name.clone(),
+        midi_key: u.key,
+        display: u.display.to_string(),
+        notehead: u.notehead.clone(),
+    };
+    match first {
+        Some(FlatElem::Event(ev)) => vec![ev.unpitched.as_ref().map(setting)],
+        Some(FlatElem::Tie(tie)) => vec![tie.id.and_then(|id| links.unpitched.get(&NoteRef { measure, id })).map(setting)],
+        Some(FlatElem::Chord(chord)) => chord.events.iter().map(|ev| ev.unpitched.as_ref().map(setting)).collect(),
+        _ => vec![None],
+    }
+}
+
 /// 記譜値集合D（全音符、2分音符、4分音符、8分音符、16分音符、付点2分音符、付点4分音符など）
 fn get_note_durations() -> Vec<Ratio<i32>> {
     vec![
@@ -133,3 +377,88 @@ fn flatten_elements<'a>(elements: &'a [ScoreElement], out: &mut Vec<FlatElem<'a>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_score;
+    use crate::processor::process_score;
+
+    /// 最初のパートの指定した小節をグルーピングした音の (id, 音価, タイ)
+    fn grouped(src: &str, measure: usize) -> Vec<(usize, String, bool)> {
+        let score = process_score(parse_score(src).expect("parse"));
+        let part = &score.parts[0];
+        let mut links = TieLinks::default();
+        for m in &part.measures {
+            for beat in m.voices.iter().flat_map(|v| v.beats.iter()) {
+                links.add_elements(m.number, &beat.elements);
+            }
+        }
+        let m = part.measures.iter().find(|m| m.number == measure).expect("measure");
+        let elements: Vec<ScoreElement> = m.voices[0].beats.iter().flat_map(|b| b.elements.iter().cloned()).collect();
+        group_measure_elements(measure, 1, 1, &elements, &links).into_iter()
+            .map(|note| (note.id, note.attributes[0].duration.clone(), note.attributes[0].tie))
+            .collect()
+    }
+
+    #[test]
+    fn dash_tie_keeps_both_notes_joined_by_tie_flag() {
+        let src = "#[Part(A)]\n1: 4/4 [C5-, C5, D5, t]\n2: [E5, F5, r, G5-]\n3: [G5, r, r, r]\n";
+        // `C5-, C5` は2つの音のまま、前の音のタイで結ぶ。`D5, t` は1つの音にまとめる
+        assert_eq!(grouped(src, 1), vec![
+            (1, "1/1".to_string(), true),
+            (2, "1/1".to_string(), false),
+            (3, "2/1".to_string(), false),
+        ]);
+        // 小節をまたぐ `G5-` も、次の小節の `G5` とは別の音
+        let tied: Vec<bool> = grouped(src, 2).into_iter().map(|(_, _, tie)| tie).collect();
+        assert_eq!(tied, vec![false, false, false, true]);
+        assert_eq!(grouped(src, 3)[0], (1, "1/1".to_string(), false));
+    }
+
+    #[test]
+    fn chord_ties_are_flagged_per_member() {
+        let score = process_score(parse_score("#[Part(A)]\n1: 4/4 [{C5, E5, G5}, {t, F5, t}, {C5-, E5, G5}, {C5, D5, G5}]\n").expect("parse"));
+        let mut links = TieLinks::default();
+        let elements: Vec<ScoreElement> = score.parts[0].measures[0].voices[0].beats.iter()
+            .flat_map(|b| b.elements.iter().cloned())
+            .collect();
+        links.add_elements(1, &elements);
+        let ties: Vec<Vec<bool>> = group_measure_elements(1, 1, 1, &elements, &links).iter()
+            .map(|note| note.attributes.iter().map(|a| a.tie).collect())
+            .collect();
+        assert_eq!(ties, vec![
+            vec![true, false, true],
+            vec![false, false, false],
+            vec![true, false, false],
+            vec![false, false, false],
+        ]);
+    }
+
+    #[test]
+    fn grace_notes_come_before_their_main_note() {
+        let score = process_score(parse_score("#[Part(A)]\n1: 4/4 [(D5)C5, (/D5 E5)F5, G5, A5]\n").expect("parse"));
+        let elements: Vec<ScoreElement> = score.parts[0].measures[0].voices[0].beats.iter()
+            .flat_map(|b| b.elements.iter().cloned())
+            .collect();
+        let notes: Vec<(usize, String, String, Option<usize>)> = group_measure_elements(1, 1, 1, &elements, &TieLinks::default())
+            .into_iter()
+            .map(|note| {
+                let a = &note.attributes[0];
+                (note.id, a.r#type.clone(), a.duration.clone(), a.grace_for)
+            })
+            .collect();
+        let entry = |id: usize, kind: &str, duration: &str, grace_for: Option<usize>| {
+            (id, kind.to_string(), duration.to_string(), grace_for)
+        };
+        assert_eq!(notes, vec![
+            entry(1, "appoggiatura", "1/2", Some(2)),
+            entry(2, "note", "1/1", None),
+            entry(3, "acciaccatura", "1/4", Some(5)),
+            entry(4, "acciaccatura", "1/4", Some(5)),
+            entry(5, "note", "1/1", None),
+            entry(6, "note", "1/1", None),
+            entry(7, "note", "1/1", None),
+        ]);
+    }
+}
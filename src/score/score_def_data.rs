@@ -30,6 +30,10 @@ pub struct KeySignatureSetting {
 #[derive(Serialize, Deserialize)]
 pub struct PartSetting {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instrument: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transposition: Vec<TranspositionSetting>,
     pub staves: Vec<StavesSetting>,
     pub dynamics: Vec<DynamicsSetting>,
     pub notes: Vec<NoteEntry>,
@@ -39,10 +43,24 @@ pub struct PartSetting {
 pub struct StavesSetting {
     pub measure: usize,
     pub position: f32,
-    pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,    // "single" または "grand"（staff_countとどちらか必須）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staff_count: Option<u8>,   // 1~8
+    #[serde(default)]
+    pub clef: Vec<String>,         // 譜表ごとのClef型（"Treble" など）
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub chromatic_assignment: bool, // trueの場合、音符のpitchを音高ではなくMIDIキーとして扱う（打楽器譜）
     pub lines: Vec<u8>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TranspositionSetting {
+    pub measure: usize,
+    pub position: f32,
+    pub transposition_intaval: i32, // 実音との差（半音単位、Bbクラリネットなら-2）
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DynamicsSetting {
     pub measure: usize,
@@ -53,14 +71,66 @@ pub struct DynamicsSetting {
 #[derive(Serialize, Deserialize)]
 pub struct NoteEntry {
     pub measure: usize,
+    #[serde(default = "default_staff_or_voice")]
+    pub staff: usize, // 譜表番号（未記入の場合は1）
+    #[serde(default = "default_staff_or_voice")]
+    pub voice: usize, // 声部番号（未記入の場合は1）
     pub id: usize,
     pub attributes: Vec<NoteAttributes>,
     pub source_ids: Option<Vec<usize>>, // 和音の構成音idリスト等
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lyrics: Vec<LyricSetting>, // VSCの `#[Lyrics]` から生成（番ごとに1つ）
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LyricSetting {
+    pub verse: usize,
+    pub text: String,
+    pub syllabic: String, // "single", "begin", "middle" または "end"（begin・middleは次の音節へハイフンを書く）
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub extender: bool,   // 次の音節までの音（メリスマ・タイ）に線を延ばす
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct NoteAttributes {
-    pub r#type: String,      // "note" or "rest"
-    pub accidental: String,  // "None" for rest
+    pub r#type: String,      // "note", "rest", "acciaccatura" or "appoggiatura"
+    pub accidental: String,  // Accidental型の名前 (例: "Sharp", "QuarterFlat")。restや未記入は"None"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub articulations: Vec<String>, // Articulation型の名前 (例: "staccato")。VSCの `C5.stacc` などから生成
     pub duration: String,    // 例: "2/1"
+    #[serde(default)]
+    pub tie: bool,           // trueの場合、次の音へタイで結ぶ（和音は構成音ごと）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch_slide: Option<PitchSlide>, // 音高を変えるタイ（t(C#5), t+50c）から生成
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grace_for: Option<usize>, // 前打音の場合、付く主音のid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measure_rest: Option<MeasureRestSetting>, // 小節全体の休符（VSCの `R`）。小節の中央に1つの休符として描く
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unpitched: Option<UnpitchedSetting>, // 名前で書いた打楽器の音（VSCの `kick`, `hh.open`）
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnpitchedSetting {
+    pub name: String,     // ドラムマップでの名前（例: "hh.open"）
+    pub midi_key: u8,     // 鳴らすMIDIキー
+    pub display: String,  // 譜表上の位置（例: "G5"）
+    pub notehead: String, // 符頭（"normal", "x", "circle_x" など）
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MeasureRestSetting {
+    pub first_measure: usize, // 連続する休符の最初の小節（`12-20: R` なら12）
+    pub measure_count: usize, // 連続する小節数。2以上なら1つの複数小節休符にまとめて描く
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PitchSlide {
+    pub r#type: String,           // "glissando" または "portamento"
+    pub slide_end_measure: usize,
+    pub slide_end_id: usize,
+}
+
+fn default_staff_or_voice() -> usize {
+    1
 }
@@ -0,0 +1,520 @@
+use vec_score_drawer::data::{Chord, Event, GraceKind, Score, ScoreElement, Syllabic};
+use vec_score_drawer::parser::{parse_score, parse_score_file};
+use vec_score_drawer::processor::process_score;
+use vec_score_drawer::printer::{expanded_syntax, print_score};
+use num_rational::Ratio;
+use std::path::Path;
+
+/// 解析に成功することを確かめて Score を返す
+fn parse(src: &str) -> Score {
+    parse_score(src).unwrap_or_else(|errors| {
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        panic!("parse failed:\n{}", messages.join("\n"))
+    })
+}
+
+/// 最初のパートの指定した小節・声部1の拍ごとの要素
+fn beat_elements(score: &Score, measure: usize) -> Vec<&ScoreElement> {
+    let measure = score.parts[0].measures.iter().find(|m| m.number == measure).expect("measure");
+    measure.voices[0].beats.iter().flat_map(|b| b.elements.iter()).collect()
+}
+
+fn chord(elem: &ScoreElement) -> &Chord {
+    match elem {
+        ScoreElement::Chord(chord) => chord,
+        other => panic!("expected a chord, got {:?}", other),
+    }
+}
+
+#[test]
+fn chord_slide_bends_from_member_at_same_position() {
+    let score = parse("#[Part(A)]\n1: 4/4 [{C5-, E5-}, {t, t+50c}, {G5, B5}, {t+100c, t}]\n");
+    let elems = beat_elements(&score, 1);
+    let (first, second) = (chord(elems[0]), chord(elems[1]));
+    let slide = &second.events[1];
+    assert!(slide.slide);
+    assert_eq!(slide.pitch_cents, Some(7650));
+    assert_eq!(slide.tied_from.map(|r| r.id), first.events[1].id);
+    assert_eq!(second.events[0].pitch_cents, Some(7200));
+
+    let (third, fourth) = (chord(elems[2]), chord(elems[3]));
+    assert_eq!(fourth.events[0].pitch_cents, Some(8000));
+    assert_eq!(fourth.events[0].tied_from.map(|r| r.id), third.events[0].id);
+    assert_eq!(fourth.events[1].pitch_cents, Some(8300));
+    assert!(!fourth.events[1].slide);
+}
+
+#[test]
+fn relative_lowercase_notes_fill_whole_beats() {
+    let score = parse("#[Part(A)]\n#[Relative]\n1: 4/4 [c, d, e, f']\n");
+    let cents: Vec<_> = beat_elements(&score, 1).iter().map(|e| match e {
+        ScoreElement::Event(ev) => ev.pitch_cents,
+        other => panic!("expected a note, got {:?}", other),
+    }).collect();
+    assert_eq!(cents, vec![Some(6000), Some(6200), Some(6400), Some(7700)]);
+}
+
+/// 最初のパートの指定した小節・声部1の拍ごとの分母単位数
+fn beat_units(score: &Score, measure: usize) -> Vec<usize> {
+    let measure = score.parts[0].measures.iter().find(|m| m.number == measure).expect("measure");
+    measure.voices[0].beats.iter().map(|b| b.units).collect()
+}
+
+#[test]
+fn compound_pickup_counts_units_beyond_beat_groups() {
+    let score = parse("#[Part(A)]\n0: 6/8 pickup [r, r, G4]\n1: [C5, D5]\n");
+    assert_eq!(beat_units(&score, 0), vec![1, 1, 1]);
+    assert_eq!(beat_units(&score, 1), vec![3, 3]);
+
+    let score = parse("#[Part(A)]\n0: 2+2+3/8 pickup [A4, B4, C5, D5]\n1: [C5, D5, E5]\n");
+    assert_eq!(beat_units(&score, 0), vec![1, 1, 1, 1]);
+
+    let score = parse("#[Part(A)]\n0: 6/8 pickup [G4]\n1: [C5, D5]\n");
+    assert_eq!(beat_units(&score, 0), vec![3]);
+    assert!(parse_score("#[Part(A)]\n0: 6/8 pickup [r, r, r, r, r, r, G4]\n").is_err());
+}
+
+#[test]
+fn compound_partial_measure_never_exceeds_meter() {
+    let score = parse("#[Part(A)]\n1: 6/8 partial(3) [C5, D5, E5]\n");
+    assert_eq!(beat_units(&score, 1), vec![1, 1, 1]);
+
+    let score = parse("#[Part(A)]\n1: 2+2+3/8 partial(2) [C5, D5]\n");
+    assert_eq!(beat_units(&score, 1), vec![2, 2]);
+
+    let score = parse("#[Part(A)]\n1: 6/8 partial(1) [C5]\n");
+    assert_eq!(beat_units(&score, 1), vec![3]);
+    assert!(parse_score("#[Part(A)]\n1: 6/8 partial(7) [C5, D5, E5, F5, G5, A5, B5]\n").is_err());
+}
+
+/// tests/fixtures/roundtrip の各VSCを書き出して読み直すと同じ Score になり、書き出しは変わらない
+#[test]
+fn printed_scores_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/roundtrip");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir).expect("fixture directory")
+        .map(|entry| entry.expect("fixture").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "vsc"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let score = parse_score_file(&path).unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+        let printed = print_score(&score);
+        let again = parse_score(&printed).unwrap_or_else(|e| panic!("{}: reparse failed: {:?}\n{}", path.display(), e, printed));
+        assert_eq!(format!("{:?}", again), format!("{:?}", score), "{} does not round-trip:\n{}", path.display(), printed);
+        assert_eq!(print_score(&again), printed, "{} is not idempotent", path.display());
+    }
+}
+
+/// 要素のidを記譜順に集める（和音は構成音の後に和音自体）
+fn element_ids(elements: &[&ScoreElement]) -> Vec<u64> {
+    fn visit(elem: &ScoreElement, out: &mut Vec<u64>) {
+        match elem {
+            ScoreElement::Event(ev) => out.extend(ev.id),
+            ScoreElement::Tie(tie) => out.extend(tie.id),
+            ScoreElement::Chord(chord) => {
+                out.extend(chord.events.iter().filter_map(|ev| ev.id));
+                out.extend(chord.id);
+            }
+            ScoreElement::Subdivision(sub) => sub.elements.iter().for_each(|e| visit(e, out)),
+        }
+    }
+    let mut out = Vec::new();
+    elements.iter().for_each(|e| visit(e, &mut out));
+    out
+}
+
+#[test]
+fn anchored_ids_stay_fixed_when_notes_are_inserted() {
+    let score = parse("#[Part(A)]\n1: 4/4 [C5, [D5@3, E5], F5, {G5, B5}@1]\n");
+    assert_eq!(element_ids(&beat_elements(&score, 1)), vec![2, 3, 4, 5, 6, 7, 1]);
+    let score = parse("#[Part(A)]\n1: 4/4 [[B4, C5], [D5@3, E5], F5, {G5, B5}@1]\n");
+    assert_eq!(element_ids(&beat_elements(&score, 1)), vec![2, 4, 3, 5, 6, 7, 8, 1]);
+}
+
+#[test]
+fn anchor_collisions_are_reported() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5@2, D5@2, E5, F5]\n").expect_err("duplicate anchor");
+    assert!(errors.iter().any(|e| e.message.contains("@2")), "{:?}", errors);
+    // 別の小節なら同じ番号を使える
+    assert!(parse_score("#[Part(A)]\n1: 4/4 [C5@2, D5, E5, F5]\n2: [C5@2, D5, E5, F5]\n").is_ok());
+}
+
+#[test]
+fn voice_without_meter_reports_error_with_span() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5]\n#[Voice(2)]\n2: [C4, D4, E4, F4]\n").expect_err("no meter");
+    let error = errors.iter().find(|e| e.message.starts_with("No meter for staff 1 voice 2")).expect("meter error");
+    assert_eq!(error.line, Some(3));
+    assert!(error.span.is_some());
+    assert!(errors.iter().all(|e| !e.message.contains("Internal error")));
+}
+
+#[test]
+fn dash_tie_without_matching_note_is_reported() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5-, D5, E5, F5]\n").expect_err("dangling tie");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].message.starts_with("Tie `C5-` is not followed"));
+    assert_eq!(errors[0].span.map(|s| s.start), Some(13));
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [{C5-, E5-}, {C5, G5}, r, F5-]\n").expect_err("dangling ties");
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages.len(), 2, "{:?}", messages);
+    assert!(messages[0].starts_with("Tie `E5-` is not followed"));
+    assert!(messages[1].starts_with("Tie `F5-` at the end of staff 1 voice 1"));
+
+    // 同じ高さの音・t・和音の構成音・次の小節へのタイは続いている
+    parse("#[Part(A)]\n1: 4/4 [C5-, C5, D5-, t]\n2: [{E5-, G5}, {E5, B5}, F5-, [F5, A5-]]\n3: [A5, r, r, r]\n");
+}
+
+#[test]
+fn out_of_order_measure_does_not_fill_a_gap() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5]\n3: [C5, D5, E5, F5]\n2: [C5, D5, E5, F5]\n").expect_err("bad numbering");
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert!(messages.iter().any(|m| m.starts_with("Measure 2 is out of order")), "{:?}", messages);
+    assert!(messages.iter().any(|m| m.contains("skip from 1 to 3")), "{:?}", messages);
+}
+
+/// 要素の音高（セント）。和音は構成音の並び、`[...]` は中の要素を順に並べる
+fn element_cents(elem: &ScoreElement) -> Vec<Option<u16>> {
+    match elem {
+        ScoreElement::Event(ev) => vec![ev.pitch_cents],
+        ScoreElement::Tie(tie) => vec![tie.pitch_cents],
+        ScoreElement::Chord(chord) => chord.events.iter().map(|ev| ev.pitch_cents).collect(),
+        ScoreElement::Subdivision(sub) => sub.elements.iter().flat_map(element_cents).collect(),
+    }
+}
+
+#[test]
+fn transposed_motif_ties_and_slides_are_shifted_once() {
+    let score = parse(concat!(
+        "let m = [C5, t, E5, t+50c]\n",
+        "let n = [C5-, C5, E5, t(F5)]\n",
+        "let c = [{C5, E5}, {t, t+50c}, D5, r]\n",
+        "#[Part(A)]\n",
+        "1: 4/4 [m^+2]\n",
+        "2: [n^+2]\n",
+        "3: [c^-12]\n",
+    ));
+    let cents = |measure| beat_elements(&score, measure).iter().map(|e| element_cents(e)).collect::<Vec<_>>();
+    assert_eq!(cents(1), vec![vec![Some(7400)], vec![Some(7400)], vec![Some(7800)], vec![Some(7850)]]);
+    assert_eq!(cents(2), vec![vec![Some(7400)], vec![Some(7400)], vec![Some(7800)], vec![Some(7900)]]);
+    assert_eq!(cents(3)[1], vec![Some(6000), Some(6450)]);
+
+    let printed = print_score(&score);
+    assert!(printed.contains("t(F#5+50c)"), "{}", printed);
+    assert!(printed.contains("D5-,") && printed.contains("t(G5)"), "{}", printed);
+}
+
+#[test]
+fn partial_measure_longer_than_meter_is_reported() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 partial(6) [C5, D5, E5, F5, G5, A5]\n").expect_err("too long");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].message.starts_with("Partial measure length (6) exceeds meter 4/4"), "{}", errors[0].message);
+    assert_eq!(errors[0].line, Some(1));
+    assert_eq!(errors[0].span.map(|s| (s.start, s.end)), Some((7, 17)));
+
+    let score = parse("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5]\n2: partial(4) [C5, D5, E5, F5]\n");
+    assert_eq!(beat_units(&score, 2), vec![1, 1, 1, 1]);
+}
+
+#[test]
+fn format_does_not_overwrite_sources_with_expanded_syntax() {
+    let source = "let m = [C5, D5, E5, F5]\n#[Part(A)]\n#[Relative]\n1: 4/4 [m] // motif\n";
+    assert_eq!(expanded_syntax(source), vec![(0, "motif definition `let`"), (2, "#[Relative]")]);
+    assert!(expanded_syntax("#[Part(A)]\n// let m = [C5]\n1: 4/4 [C5, D5, E5, F5]\n").is_empty());
+
+    let dir = std::env::temp_dir().join(format!("vsc_format_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("motif.vsc");
+    std::fs::write(&path, source).unwrap();
+    let run = |output: &Path| std::process::Command::new(env!("CARGO_BIN_EXE_vec_score_drawer"))
+        .args(["format", "--input"]).arg(&path).arg("--output").arg(output)
+        .output().unwrap();
+
+    let result = run(&path);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Refusing to format"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), source);
+
+    // 別のファイルへは展開した形で書き出す
+    let other = dir.join("formatted.vsc");
+    run(&other);
+    let formatted = std::fs::read_to_string(&other).unwrap();
+    assert!(formatted.contains("[C5, D5, E5, F5]") && !formatted.contains("let "), "{}", formatted);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 最初のパートの指定した小節・声部1の音の高さ（セント）
+fn measure_cents(score: &Score, measure: usize) -> Vec<Option<u16>> {
+    beat_elements(score, measure).iter().flat_map(|e| element_cents(e)).collect()
+}
+
+#[test]
+fn includes_expand_relative_to_the_including_file() {
+    let score = parse_score_file("tests/fixtures/include/main.vsc").expect("parse");
+    let numbers: Vec<usize> = score.parts[0].measures.iter().map(|m| m.number).collect();
+    assert_eq!(numbers, vec![1, 2, 3, 4]);
+    // strings/ から ../shared/ を読み込み、別のファイルで定義したモチーフを使う
+    assert_eq!(measure_cents(&score, 2), vec![Some(7900), Some(8100), Some(8300), Some(8400)]);
+    assert_eq!(measure_cents(&score, 3), vec![Some(6700), Some(6500), Some(6400), Some(6200)]);
+    assert_eq!(measure_cents(&score, 4), vec![Some(7900), Some(7700), Some(7600), Some(7400)]);
+}
+
+#[test]
+fn include_cycle_is_reported() {
+    let errors = parse_score_file("tests/fixtures/include/cycle/a.vsc").expect_err("cycle");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    let error = &errors[0];
+    assert!(error.message.starts_with("Include cycle: "), "{}", error.message);
+    assert!(error.message.contains("a.vsc -> ") && error.message.contains("b.vsc -> "), "{}", error.message);
+    assert!(error.file.as_ref().is_some_and(|f| f.ends_with("b.vsc")), "{:?}", error.file);
+    assert_eq!(error.line, Some(0));
+}
+
+#[test]
+fn errors_in_included_files_name_the_file_and_line() {
+    let errors = parse_score_file("tests/fixtures/include/error/main.vsc").expect_err("errors");
+    let bad = errors.iter().find(|e| e.file.as_ref().is_some_and(|f| f.ends_with("bad.vsc"))).expect("error in bad.vsc");
+    assert_eq!(bad.line, Some(1));
+    assert_eq!(bad.span.map(|s| s.start), Some(12));
+    assert!(bad.to_string().contains("bad.vsc: Line 2, Col 13"), "{}", bad);
+
+    let missing = errors.iter().find(|e| e.message.starts_with("Cannot read included file")).expect("missing file");
+    assert!(missing.message.contains("missing.vsc"), "{}", missing.message);
+    assert!(missing.file.as_ref().is_some_and(|f| f.ends_with("error/main.vsc")), "{:?}", missing.file);
+    assert_eq!(missing.line, Some(3));
+}
+
+#[test]
+fn whole_measure_rests_fill_ranges_and_compound_meters() {
+    let score = parse("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5]\n2-4: R\n5: [G5, r, r, r]\n6: 6/8 R\n7: [C5, D5]\n");
+    let rests: Vec<(usize, Option<(usize, usize)>)> = score.parts[0].measures.iter()
+        .map(|m| (m.number, m.voices[0].measure_rest.as_ref().map(|r| (r.first, r.count))))
+        .collect();
+    assert_eq!(rests, vec![
+        (1, None), (2, Some((2, 3))), (3, Some((2, 3))), (4, Some((2, 3))), (5, None), (6, Some((6, 1))), (7, None),
+    ]);
+    // 全休符は拍子の単位数すべてを占める1つの拍になる
+    assert_eq!(beat_units(&score, 3), vec![4]);
+    assert_eq!(beat_units(&score, 6), vec![6]);
+    assert_eq!(beat_units(&score, 7), vec![3, 3]);
+}
+
+#[test]
+fn whole_measure_rest_cannot_share_a_measure() {
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [R, C5, D5, E5]\n").expect_err("mixed");
+    assert!(errors[0].message.starts_with("Whole-measure rest `R` must be the only content"), "{:?}", errors);
+    assert_eq!(errors[0].span.map(|s| s.start), Some(8));
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5, D5, E5, F5]\n2-3: [C5, D5, E5, F5]\n").expect_err("range");
+    assert!(errors[0].message.starts_with("A measure range (2-3) can only hold"), "{:?}", errors);
+
+    let errors = parse_score("#[Part(A)]\n0: 4/4 pickup R\n").expect_err("pickup");
+    assert!(errors[0].message.starts_with("Whole-measure rest `R` cannot be used in a pickup"), "{:?}", errors);
+}
+
+#[test]
+fn several_errors_are_reported_with_hints_and_spans() {
+    let src = "#[Part(A)]\n1: 4/4 [Cs5, D5.stac, E5!mff, [F5, G5]\n2: [C5, D5, E5, F5]]\n";
+    let errors = parse_score(src).expect_err("errors");
+    let found: Vec<(Option<usize>, Option<usize>, &str)> = errors.iter()
+        .map(|e| (e.line, e.span.map(|s| s.start), e.message.as_str()))
+        .collect();
+    assert_eq!(found, vec![
+        (Some(1), Some(8), "Invalid pitch `Cs5`: Invalid octave (did you mean `C#5`?)"),
+        (Some(1), Some(13), "Unknown articulation `.stac` (did you mean `.stacc`?)"),
+        (Some(1), Some(22), "Unknown dynamic `!mff` (did you mean `!mf`?)"),
+        (Some(1), Some(30), "Unmatched '[' (missing ']')"),
+        (Some(2), Some(18), "Unmatched ']' (no opening '[')"),
+    ]);
+}
+
+#[test]
+fn unknown_instrument_is_reported_with_span() {
+    let errors = parse_score("#[Part(Cello, instrument=celo)]\n1: 4/4 [C3, D3, E3, F3]\n").expect_err("unknown");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].message, "Unknown instrument 'celo' (did you mean `cello`?)");
+    assert_eq!(errors[0].line, Some(0));
+    assert_eq!(errors[0].span.map(|s| (s.start, s.end)), Some((14, 29)));
+
+    let score = parse("#[Part(Bass, instrument=double_bass)]\n1: 4/4 [C3, D3, E3, F3]\n");
+    assert_eq!(score.parts[0].instrument.as_deref(), Some("double_bass"));
+}
+
+/// 要素の長さ（4分音符 = 1）。`[...]` は中の要素を順に並べる
+fn leaf_durations(elem: &ScoreElement, out: &mut Vec<Ratio<i32>>) {
+    match elem {
+        ScoreElement::Event(ev) => out.push(ev.duration),
+        ScoreElement::Tie(tie) => out.push(tie.duration),
+        ScoreElement::Chord(chord) => out.push(chord.events[0].duration),
+        ScoreElement::Subdivision(sub) => sub.elements.iter().for_each(|e| leaf_durations(e, out)),
+    }
+}
+
+#[test]
+fn weighted_elements_share_the_beat_in_proportion() {
+    let score = process_score(parse("#[Part(A)]\n1: 4/4 [[C5*3, D5], [E5, F5*2, G5], [D5*2, [E5, F5]], [r, G5*3]]\n"));
+    let beats: Vec<Vec<Ratio<i32>>> = beat_elements(&score, 1).iter().map(|e| {
+        let mut out = Vec::new();
+        leaf_durations(e, &mut out);
+        out
+    }).collect();
+    let r = Ratio::new;
+    assert_eq!(beats, vec![
+        vec![r(3, 4), r(1, 4)],
+        vec![r(1, 4), r(1, 2), r(1, 4)],
+        vec![r(2, 3), r(1, 6), r(1, 6)],
+        vec![r(1, 4), r(3, 4)],
+    ]);
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5*2, D5, E5, F5]\n").expect_err("top-level weight");
+    assert_eq!(errors[0].message, "Length weight `*N` is only allowed inside '[...]'");
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [{C5*2, E5}, D5, E5, F5]\n").expect_err("chord weight");
+    assert_eq!(errors[0].message, "Length weight `*N` cannot be used inside a chord");
+}
+
+#[test]
+fn staff_headers_assign_voices_to_staves() {
+    let score = parse(concat!(
+        "#[Part(Piano, instrument=piano)]\n",
+        "1: 4/4 [C5, D5, E5, F5]\n",
+        "#[Voice(2)]\n",
+        "1: [A4, B4, C5, D5]\n",
+        "#[Staff(2)]\n",
+        "1: [C3, D3, E3, F3]\n",
+        "2: [G3, r, r, r]\n",
+        "#[Staff(1)]\n",
+        "2: [G5, r, r, r]\n",
+    ));
+    let part = &score.parts[0];
+    assert_eq!(part.staff_count, 2);
+    let layout: Vec<Vec<(usize, usize)>> = part.measures.iter()
+        .map(|m| m.voices.iter().map(|v| (v.staff, v.number)).collect())
+        .collect();
+    // `#[Staff(n)]` で声部は1に戻り、声部は書いた順によらず譜表順に並ぶ
+    assert_eq!(layout, vec![vec![(1, 1), (1, 2), (2, 1)], vec![(1, 1), (2, 1)]]);
+
+    let errors = parse_score("#[Part(A)]\n#[Staff(9)]\n1: 4/4 [C5, D5, E5, F5]\n").expect_err("staff 9");
+    assert_eq!(errors[0].message, "Invalid staff number '9': expected an integer from 1 to 8");
+    let errors = parse_score("#[Part(A, clef=treble)]\n1: 4/4 [C5, D5, E5, F5]\n#[Staff(2)]\n1: [C3, D3, E3, F3]\n").expect_err("clefs");
+    assert!(errors[0].message.contains("uses staff 2 but its header lists 1 clef(s)"), "{:?}", errors);
+}
+
+#[test]
+fn cents_offsets_literals_and_slides_set_pitch_cents() {
+    let score = parse("#[Part(A)]\n1: 4/4 [C5+31c, 72-14c, 6031c, [C5, t(D5-25c)]]\n2: [C5+31c, t+50c, t-100c, t]\n");
+    assert_eq!(measure_cents(&score, 1), vec![Some(7231), Some(7186), Some(6031), Some(7200), Some(7375)]);
+    assert_eq!(measure_cents(&score, 2), vec![Some(7231), Some(7281), Some(7181), Some(7181)]);
+
+    // スライドは接続元を指し、音高を変えない `t` はスライドではない
+    let elems = beat_elements(&score, 2);
+    let ids: Vec<Option<u64>> = elems.iter().map(|e| match e {
+        ScoreElement::Event(ev) => ev.id,
+        ScoreElement::Tie(tie) => tie.id,
+        other => panic!("expected a note or tie, got {:?}", other),
+    }).collect();
+    let ties: Vec<(bool, Option<u64>)> = elems[1..].iter().map(|e| match e {
+        ScoreElement::Tie(tie) => (tie.slide, tie.tied_from.map(|r| r.id)),
+        other => panic!("expected a tie, got {:?}", other),
+    }).collect();
+    assert_eq!(ties, vec![(true, ids[0]), (true, ids[1]), (false, ids[2])]);
+
+    for (src, message) in [
+        ("12800c", "out of range"),
+        ("C5+xc", "Invalid cents offset"),
+        ("t+50c", "no preceding note"),
+    ] {
+        let errors = parse_score(&format!("#[Part(A)]\n1: 4/4 [{}, D5, E5, F5]\n", src)).expect_err(src);
+        assert!(errors[0].message.contains(message), "{}: {:?}", src, errors);
+    }
+}
+
+#[test]
+fn chord_ties_link_members_individually() {
+    let score = parse("#[Part(A)]\n1: 4/4 [{C5, E5, G5}, {t, F5, t}, {C5-, E5, G5}, {C5, D5, G5}]\n");
+    let elems = beat_elements(&score, 1);
+    let (first, second) = (chord(elems[0]), chord(elems[1]));
+    let tied: Vec<Option<u64>> = second.events.iter().map(|ev| ev.tied_from.map(|r| r.id)).collect();
+    assert_eq!(tied, vec![first.events[0].id, None, first.events[2].id]);
+    assert_eq!(element_cents(elems[1]), vec![Some(7200), Some(7700), Some(7900)]);
+
+    // `-` は次の和音の同じ高さの構成音にだけ続く
+    let (third, fourth) = (chord(elems[2]), chord(elems[3]));
+    let tied: Vec<Option<u64>> = fourth.events.iter().map(|ev| ev.tied_from.map(|r| r.id)).collect();
+    assert_eq!(tied, vec![third.events[0].id, None, None]);
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [{C5, E5}, {t, t, t}, D5, E5]\n").expect_err("extra member");
+    assert!(errors[0].message.starts_with("Chord tie `t` at position 3 has no matching note"), "{:?}", errors);
+}
+
+fn event(elem: &ScoreElement) -> &Event {
+    match elem {
+        ScoreElement::Event(ev) => ev,
+        other => panic!("expected a note, got {:?}", other),
+    }
+}
+
+#[test]
+fn grace_notes_attach_to_the_following_note_without_taking_a_beat() {
+    let score = parse("#[Part(A)]\n1: 4/4 [(D5)C5, (/D5 E5)F5, G5, A5]\n");
+    let elems = beat_elements(&score, 1);
+    assert_eq!(elems.len(), 4);
+    let graces = |i: usize| -> Vec<(GraceKind, Option<u16>)> {
+        event(elems[i]).grace_notes.iter().map(|g| (g.kind, g.pitch_cents)).collect()
+    };
+    assert_eq!(graces(0), vec![(GraceKind::Appoggiatura, Some(7400))]);
+    assert_eq!(graces(1), vec![(GraceKind::Acciaccatura, Some(7400)), (GraceKind::Acciaccatura, Some(7600))]);
+    assert!(event(elems[2]).grace_notes.is_empty());
+
+    // 前打音は主音より前の独自のidを持つ
+    let (grace, main) = (event(elems[1]).grace_notes[0].id.unwrap(), event(elems[1]).id.unwrap());
+    assert!(grace < main);
+    let mut ids: Vec<u64> = elems.iter().map(|e| event(e).id.unwrap())
+        .chain(elems.iter().flat_map(|e| event(e).grace_notes.iter().map(|g| g.id.unwrap())))
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), 7);
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [{(D5)C5, E5}, D5, E5, F5]\n").expect_err("grace in chord");
+    assert_eq!(errors[0].message, "Grace notes cannot be attached to a chord member");
+}
+
+#[test]
+fn lyrics_align_to_notes_skipping_rests_and_ties() {
+    let score = parse(concat!(
+        "#[Part(A)]\n",
+        "1: 4/4 [C5, D5-, D5, r]\n",
+        "2: [{E5, G5}, t, F5, G5]\n",
+        "#[Lyrics]\n",
+        "1: Hal- le-\n",
+        "2: lu- jah_ _\n",
+        "#[Lyrics(verse=2)]\n",
+        "1: one two\n",
+    ));
+    let part = &score.parts[0];
+    let ids = |measure: usize| -> Vec<u64> {
+        beat_elements(&score, measure).iter().map(|e| match e {
+            ScoreElement::Event(ev) => ev.id.unwrap(),
+            ScoreElement::Chord(chord) => chord.id.unwrap(),
+            ScoreElement::Tie(tie) => tie.id.unwrap(),
+            other => panic!("unexpected {:?}", other),
+        }).collect()
+    };
+    let (m1, m2) = (ids(1), ids(2));
+    let lyrics: Vec<(usize, usize, u64, &str, Syllabic, bool)> = part.lyrics.iter()
+        .map(|l| (l.verse, l.note.measure, l.note.id, l.text.as_str(), l.syllabic, l.extender))
+        .collect();
+    // `D5-, D5` の続きの音・休符・`t` には付けず、`_` だけなら音節を付けずに次の音へ進む
+    assert_eq!(lyrics, vec![
+        (1, 1, m1[0], "Hal", Syllabic::Begin, false),
+        (1, 1, m1[1], "le", Syllabic::Middle, false),
+        (1, 2, m2[0], "lu", Syllabic::Middle, false),
+        (1, 2, m2[2], "jah", Syllabic::End, true),
+        (2, 1, m1[0], "one", Syllabic::Single, false),
+        (2, 1, m1[1], "two", Syllabic::Single, false),
+    ]);
+
+    let errors = parse_score("#[Part(A)]\n1: 4/4 [C5, D5, r, r]\n#[Lyrics]\n1: one two three\n").expect_err("too many");
+    assert_eq!(errors[0].message, "Measure 1 has only 2 note(s) to carry lyrics in staff 1 voice 1");
+    assert_eq!((errors[0].line, errors[0].span.map(|s| s.start)), (Some(3), Some(11)));
+}
@@ -2,9 +2,8 @@ use tower_lsp::{LspService, Server, Client};
 use tower_lsp::lsp_types::*;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::LanguageServer;
-// 仮定: parse_score は Result<_, ParseError> を返し、ParseError に line: Option<usize> がある
-// ParseError 型を vec_score_drawer からインポートする必要があります。
-use vec_score_drawer::parser::{parse_score, ParseError}; // ParseError をインポート (仮)
+// parse_score は Result<_, Vec<ParseError>> を返し、ParseError は line と行内の span、file を持つ
+use vec_score_drawer::parser::{parse_score, parse_score_with_path};
 
 #[derive(Debug)]
 struct Backend {
@@ -50,7 +49,13 @@ impl Backend {
 
         let mut diagnostics = Vec::new();
 
-        match parse_score(text) {
+        // ファイルとして開かれている場合は #include をそのファイルからの相対パスで展開する
+        let path = uri.to_file_path().ok();
+        let result = match &path {
+            Some(path) => parse_score_with_path(text, path),
+            None => parse_score(text),
+        };
+        match result {
             Ok(_) => {
                 self.client.log_message(MessageType::INFO, "Parse successful.").await;
                 // Ok の場合は Diagnostic をクリアする (空の Vec を publish する)
@@ -58,13 +63,18 @@ impl Backend {
             Err(errs) => {
                 for err in errs {
                     self.client.log_message(MessageType::ERROR, format!("Parse error: {}", err)).await;
-                    let line_idx = err.line.unwrap_or(0);
-                    let char_end = text.lines()
-                                       .nth(line_idx)
-                                       .map_or(0, |line_text| line_text.len()) as u32;
+                    // include した別ファイルのエラーは先頭行に表示する（メッセージにファイル名が入る）
+                    let in_this_file = err.file.is_none() || err.file == path;
+                    let line_idx = if in_this_file { err.line.unwrap_or(0) } else { 0 };
+                    let line_text = text.lines().nth(line_idx).unwrap_or("");
+                    // span はバイト単位なので、LSP の UTF-16 単位の列位置に変換する
+                    let (byte_start, byte_end) = match err.span {
+                        Some(span) if in_this_file => (span.start, span.end),
+                        _ => (0, line_text.len()),
+                    };
                     let range = Range {
-                        start: Position { line: line_idx as u32, character: 0 },
-                        end:   Position { line: line_idx as u32, character: char_end },
+                        start: Position { line: line_idx as u32, character: utf16_column(line_text, byte_start) },
+                        end:   Position { line: line_idx as u32, character: utf16_column(line_text, byte_end) },
                     };
                     diagnostics.push(Diagnostic {
                         range,
@@ -88,6 +98,16 @@ impl Backend {
     }
 }
 
+/// 行内のバイトオフセットを UTF-16 の列位置に変換する
+fn utf16_column(line_text: &str, byte: usize) -> u32 {
+    let byte = byte.min(line_text.len());
+    line_text
+        .char_indices()
+        .take_while(|(i, _)| *i < byte)
+        .map(|(_, c)| c.len_utf16() as u32)
+        .sum()
+}
+
 #[tokio::main]
 async fn main() {
     // Client を Backend に渡す